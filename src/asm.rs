@@ -0,0 +1,513 @@
+// an assembler for the mnemonics of Cowgod's technical reference, as `Instruction` prints them,
+// so the Cowgod listings of the disassembler assemble again. A line holds an optional `label:`
+// and one statement, `;` starts a comment:
+//
+//     SPEED EQU 4
+//     main:   LD V0, SPEED        ; constants, labels and numbers, also `label + 2`
+//             CALL draw
+//             JP main
+//     draw:   LD I, ship
+//             DRW V0, V1, 3
+//             RET
+//     ship:   DB 0x3C, 0x42, 0xFF
+//             INCLUDE "levels.asm"
+//
+// Numbers are decimal, hex with 0x or $, or binary with 0b. Labels may be used before they are
+// defined, constants may use labels and the constants before them.
+
+use crate::chip8::PC_START;
+use crate::error::AsmError;
+use crate::instruction::Instruction;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MEMORY_END: usize = 0x10000;
+const MNEMONICS: [&str; 32] = [
+    "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SYS", "SCD", "SCU", "PLANE", "JP", "CALL", "SE", "SNE",
+    "SAVE", "LOAD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP", "PITCH", "LD",
+];
+// operands with a meaning of their own, they can't name labels or constants
+const RESERVED: [&str; 10] = ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "EQU"];
+
+/// Assembly is an assembled program.
+pub struct Assembly {
+    /// the program, to be loaded at PC_START
+    pub binary: Vec<u8>,
+    /// the address of every label
+    pub labels: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    /// symbol_map lists the labels ordered by address, one `0x0200 main` per line
+    pub fn symbol_map(&self) -> String {
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort_by_key(|(name, address)| (**address, *name));
+        let mut text = String::new();
+        for (name, address) in labels {
+            writeln!(text, "0x{:04X} {}", address, name).unwrap();
+        }
+        text
+    }
+}
+
+/// assemble assembles source text, includes are looked up relative to the current directory
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler::default();
+    assembler.read(&Rc::from("<source>"), Path::new("."), source, 0)?;
+    assembler.finish()
+}
+
+/// assemble_file assembles a source file, includes are looked up relative to the file
+pub fn assemble_file(path: &Path) -> Result<Assembly, AsmError> {
+    let name: Rc<str> = Rc::from(path.display().to_string());
+    let source = fs::read_to_string(path).map_err(|err| AsmError { file: name.to_string(), line: 0, message: err.to_string() })?;
+    let mut assembler = Assembler::default();
+    assembler.read(&name, path.parent().unwrap_or(Path::new(".")), &source, 0)?;
+    assembler.finish()
+}
+
+// where a statement came from
+#[derive(Clone)]
+struct Location {
+    file: Rc<str>,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        AsmError { file: self.file.to_string(), line: self.line, message: message.into() }
+    }
+}
+
+enum Statement {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+// the first pass reads the statements and places the labels, the second one encodes the
+// statements once every symbol is known
+#[derive(Default)]
+struct Assembler {
+    statements: Vec<(Location, Statement)>,
+    labels: BTreeMap<String, u16>,
+    constants: Vec<(Location, String, String)>,     //  name and expression, in order
+    symbols: HashMap<String, i64>,
+    size: usize,
+}
+
+impl Assembler {
+    fn read(&mut self, file: &Rc<str>, dir: &Path, source: &str, depth: usize) -> Result<(), AsmError> {
+        for (number, line) in source.lines().enumerate() {
+            let location = Location { file: file.clone(), line: number + 1 };
+            let mut text = strip_comment(line).trim();
+            if let Some((name, rest)) = split_label(text) {
+                self.define(&location, name)?;
+                self.labels.insert(name.to_string(), (PC_START as usize + self.size) as u16);
+                text = rest.trim();
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (word, rest) = split_word(text);
+            let (second, value) = split_word(rest);
+            if second.eq_ignore_ascii_case("EQU") {
+                self.define(&location, word)?;
+                self.constants.push((location, word.to_string(), value.to_string()));
+                continue;
+            }
+            let mnemonic = word.to_ascii_uppercase();
+            if mnemonic == "INCLUDE" {
+                let path = rest.strip_prefix('"').and_then(|path| path.strip_suffix('"')).ok_or_else(|| location.error("INCLUDE needs a quoted path"))?;
+                if depth == MAX_INCLUDE_DEPTH {
+                    return Err(location.error("includes are nested too deeply"));
+                }
+                let path = dir.join(path);
+                let source = fs::read_to_string(&path).map_err(|err| location.error(format!("can't read {}: {}", path.display(), err)))?;
+                let name: Rc<str> = Rc::from(path.display().to_string());
+                self.read(&name, path.parent().unwrap_or(Path::new(".")), &source, depth + 1)?;
+                continue;
+            }
+            let operands = split_operands(rest).map_err(|message| location.error(message))?;
+            let (statement, size) = match mnemonic.as_str() {
+                "DB" => {
+                    let size = operands.len();
+                    (Statement::Bytes(operands), size)
+                }
+                "DW" => {
+                    let size = 2 * operands.len();
+                    (Statement::Words(operands), size)
+                }
+                _ => {
+                    let long = mnemonic == "LD" && operands.len() == 2 && operands[0].eq_ignore_ascii_case("I") && long_operand(&operands[1]).is_some();
+                    (Statement::Instruction { mnemonic, operands }, if long { 4 } else { 2 })
+                }
+            };
+            self.size += size;
+            if PC_START as usize + self.size > MEMORY_END {
+                return Err(location.error("the program doesn't fit into memory"));
+            }
+            self.statements.push((location, statement));
+        }
+        Ok(())
+    }
+
+    // check a new label or constant name
+    fn define(&self, location: &Location, name: &str) -> Result<(), AsmError> {
+        if !is_identifier(name) || RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) || register(name).is_some() {
+            return Err(location.error(format!("{} can't be used as a name", name)));
+        }
+        if self.labels.contains_key(name) || self.constants.iter().any(|(_, constant, _)| constant == name) {
+            return Err(location.error(format!("{} is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, AsmError> {
+        self.symbols = self.labels.iter().map(|(name, address)| (name.clone(), *address as i64)).collect();
+        for (location, name, expression) in std::mem::take(&mut self.constants) {
+            let value = self.evaluate(&expression).map_err(|message| location.error(message))?;
+            self.symbols.insert(name, value);
+        }
+
+        let mut binary = Vec::with_capacity(self.size);
+        for (location, statement) in &self.statements {
+            self.encode(statement, &mut binary).map_err(|message| location.error(message))?;
+        }
+        Ok(Assembly { binary, labels: self.labels })
+    }
+
+    fn encode(&self, statement: &Statement, binary: &mut Vec<u8>) -> Result<(), String> {
+        match statement {
+            Statement::Bytes(values) => {
+                for value in values {
+                    binary.push(self.byte(value)?);
+                }
+            }
+            Statement::Words(values) => {
+                for value in values {
+                    binary.extend_from_slice(&self.value(value, 0xFFFF)?.to_be_bytes());
+                }
+            }
+            Statement::Instruction { mnemonic, operands } => {
+                let instruction = self.instruction(mnemonic, operands)?;
+                binary.extend_from_slice(&instruction.encode().to_be_bytes());
+                if let Instruction::LoadLongIndex { addr } = instruction {
+                    binary.extend_from_slice(&addr.to_be_bytes());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Instruction, String> {
+        let ops: Vec<&str> = operands.iter().map(|op| op.as_str()).collect();
+        let is = |op: &str, keyword: &str| op.eq_ignore_ascii_case(keyword);
+        let reg = |op: &str| register(op).ok_or_else(|| format!("{} is not a register", op));
+        let instruction = match (mnemonic, ops.as_slice()) {
+            ("CLS", []) => Instruction::ClearScreen,
+            ("RET", []) => Instruction::Return,
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::LowRes,
+            ("HIGH", []) => Instruction::HighRes,
+            ("AUDIO", []) => Instruction::LoadAudio,
+            ("SYS", [addr]) => Instruction::Sys { addr: self.address(addr)? },
+            ("SCD", [n]) => Instruction::ScrollDown { n: self.nibble(n)? },
+            ("SCU", [n]) => Instruction::ScrollUp { n: self.nibble(n)? },
+            ("PLANE", [n]) => Instruction::SelectPlane { n: self.nibble(n)? },
+            ("JP", [addr]) => Instruction::Jump { addr: self.address(addr)? },
+            ("JP", [v0, addr]) if register(v0) == Some(0) => Instruction::JumpOffset { addr: self.address(addr)? },
+            ("CALL", [addr]) => Instruction::Call { addr: self.address(addr)? },
+            ("SE", [x, y]) if register(y).is_some() => Instruction::SkipEqRegs { x: reg(x)?, y: reg(y)? },
+            ("SE", [x, kk]) => Instruction::SkipEqByte { x: reg(x)?, kk: self.byte(kk)? },
+            ("SNE", [x, y]) if register(y).is_some() => Instruction::SkipNeqRegs { x: reg(x)?, y: reg(y)? },
+            ("SNE", [x, kk]) => Instruction::SkipNeqByte { x: reg(x)?, kk: self.byte(kk)? },
+            ("SAVE", [x, y]) => Instruction::SaveRange { x: reg(x)?, y: reg(y)? },
+            ("LOAD", [x, y]) => Instruction::LoadRange { x: reg(x)?, y: reg(y)? },
+            ("ADD", [i, x]) if is(i, "I") => Instruction::AddIndex { x: reg(x)? },
+            ("ADD", [x, y]) if register(y).is_some() => Instruction::AddRegs { x: reg(x)?, y: reg(y)? },
+            ("ADD", [x, kk]) => Instruction::AddByte { x: reg(x)?, kk: self.byte(kk)? },
+            ("OR", [x, y]) => Instruction::Or { x: reg(x)?, y: reg(y)? },
+            ("AND", [x, y]) => Instruction::And { x: reg(x)?, y: reg(y)? },
+            ("XOR", [x, y]) => Instruction::Xor { x: reg(x)?, y: reg(y)? },
+            ("SUB", [x, y]) => Instruction::Sub { x: reg(x)?, y: reg(y)? },
+            ("SUBN", [x, y]) => Instruction::SubN { x: reg(x)?, y: reg(y)? },
+            ("SHR", [x]) => Instruction::ShiftRight { x: reg(x)?, y: reg(x)? },
+            ("SHR", [x, y]) => Instruction::ShiftRight { x: reg(x)?, y: reg(y)? },
+            ("SHL", [x]) => Instruction::ShiftLeft { x: reg(x)?, y: reg(x)? },
+            ("SHL", [x, y]) => Instruction::ShiftLeft { x: reg(x)?, y: reg(y)? },
+            ("RND", [x, kk]) => Instruction::Random { x: reg(x)?, kk: self.byte(kk)? },
+            ("DRW", [x, y, n]) => Instruction::Draw { x: reg(x)?, y: reg(y)?, n: self.nibble(n)? },
+            ("SKP", [x]) => Instruction::SkipKeyPressed { x: reg(x)? },
+            ("SKNP", [x]) => Instruction::SkipKeyNotPressed { x: reg(x)? },
+            ("PITCH", [x]) => Instruction::SetPitch { x: reg(x)? },
+            ("LD", [i, addr]) if is(i, "I") => match long_operand(addr) {
+                Some(addr) => Instruction::LoadLongIndex { addr: self.value(addr, 0xFFFF)? },
+                None => Instruction::LoadIndex { addr: self.address(addr)? },
+            },
+            ("LD", [dt, x]) if is(dt, "DT") => Instruction::SetDelayTimer { x: reg(x)? },
+            ("LD", [st, x]) if is(st, "ST") => Instruction::SetSoundTimer { x: reg(x)? },
+            ("LD", [f, x]) if is(f, "F") => Instruction::LoadFont { x: reg(x)? },
+            ("LD", [hf, x]) if is(hf, "HF") => Instruction::LoadBigFont { x: reg(x)? },
+            ("LD", [b, x]) if is(b, "B") => Instruction::StoreBcd { x: reg(x)? },
+            ("LD", [i, x]) if is(i, "[I]") => Instruction::StoreRegisters { x: reg(x)? },
+            ("LD", [r, x]) if is(r, "R") => Instruction::StoreFlags { x: reg(x)? },
+            ("LD", [x, dt]) if is(dt, "DT") => Instruction::LoadDelayTimer { x: reg(x)? },
+            ("LD", [x, k]) if is(k, "K") => Instruction::WaitKey { x: reg(x)? },
+            ("LD", [x, i]) if is(i, "[I]") => Instruction::LoadRegisters { x: reg(x)? },
+            ("LD", [x, r]) if is(r, "R") => Instruction::LoadFlags { x: reg(x)? },
+            ("LD", [x, y]) if register(y).is_some() => Instruction::LoadRegs { x: reg(x)?, y: reg(y)? },
+            ("LD", [x, kk]) => Instruction::LoadByte { x: reg(x)?, kk: self.byte(kk)? },
+            _ if MNEMONICS.contains(&mnemonic) => return Err(format!("wrong operands for {}", mnemonic)),
+            _ => return Err(format!("unknown instruction {}", mnemonic)),
+        };
+        Ok(instruction)
+    }
+
+    // a byte, negative values are stored as two's complement
+    fn byte(&self, expression: &str) -> Result<u8, String> {
+        let value = self.evaluate(expression)?;
+        if !(-0x80..=0xFF).contains(&value) {
+            return Err(format!("{} doesn't fit into a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn nibble(&self, expression: &str) -> Result<u8, String> {
+        Ok(self.value(expression, 0xF)? as u8)
+    }
+
+    fn address(&self, expression: &str) -> Result<u16, String> {
+        self.value(expression, 0xFFF)
+    }
+
+    fn value(&self, expression: &str, max: u16) -> Result<u16, String> {
+        let value = self.evaluate(expression)?;
+        if !(0..=max as i64).contains(&value) {
+            return Err(format!("{} is out of range, at most 0x{:X} is allowed", value, max));
+        }
+        Ok(value as u16)
+    }
+
+    // a sum of numbers and symbols, e.g. `sprites + 5 - 1`
+    fn evaluate(&self, expression: &str) -> Result<i64, String> {
+        let mut rest = expression.trim();
+        let mut total: i64 = 0;
+        let mut sign = 1;
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('-') {
+                sign = -sign;
+                rest = after;
+                continue;
+            }
+            if let Some(after) = rest.strip_prefix('+') {
+                rest = after;
+                continue;
+            }
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let term = rest[..end].trim();
+            let value = match parse_number(term) {
+                Some(value) => value,
+                None if term.is_empty() => return Err(format!("missing value in {}", expression.trim())),
+                None => *self.symbols.get(term).ok_or_else(|| format!("unknown symbol {}", term))?,
+            };
+            total = total.checked_add(sign * value).ok_or_else(|| format!("{} overflows", expression.trim()))?;
+            rest = &rest[end..];
+            if rest.is_empty() {
+                return Ok(total);
+            }
+            sign = 1;
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.parse().ok()
+    } else {
+        None
+    }
+}
+
+fn register(operand: &str) -> Option<u8> {
+    let digit = operand.strip_prefix(['V', 'v'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+// the address of `LD I, LONG addr`
+fn long_operand(operand: &str) -> Option<&str> {
+    let (word, rest) = split_word(operand);
+    word.eq_ignore_ascii_case("LONG").then_some(rest)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// everything before a `;` outside of quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (name, rest) = text.split_once(':')?;
+    is_identifier(name).then_some((name, rest))
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+fn split_operands(text: &str) -> Result<Vec<String>, String> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    let operands: Vec<String> = text.split(',').map(|operand| operand.trim().to_string()).collect();
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err("missing operand".to_string());
+    }
+    Ok(operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().binary
+    }
+
+    fn error(source: &str) -> (usize, String) {
+        let err = assemble(source).err().unwrap();
+        assert_eq!(err.file, "<source>");
+        (err.line, err.message)
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            CLS
+            LD V1, 0x2A
+            ADD V1, V2
+            LD I, 0x300
+            LD I, LONG 0x1234
+            DRW V1, V2, 5
+            LD [I], VF
+            SHR V3
+            JP V0, 0x400
+        ";
+        assert_eq!(
+            binary(source),
+            [0x00, 0xE0, 0x61, 0x2A, 0x81, 0x24, 0xA3, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xD1, 0x25, 0xFF, 0x55, 0x83, 0x36, 0xB4, 0x00]
+        );
+    }
+
+    #[test]
+    fn equ() {
+        let source = "
+            SPEED EQU 4
+            FAST EQU SPEED + 2
+            BACK EQU -1
+            LD V0, FAST
+            ADD V0, BACK
+            SE V0, $10 - SPEED
+        ";
+        assert_eq!(binary(source), [0x60, 0x06, 0x70, 0xFF, 0x30, 0x0C]);
+    }
+
+    #[test]
+    fn data() {
+        let source = "
+            DB 1, 0b10000001, 0xFF
+            DW 0x1234, end
+            end:
+        ";
+        assert_eq!(binary(source), [0x01, 0x81, 0xFF, 0x12, 0x34, 0x02, 0x07]);
+    }
+
+    #[test]
+    fn forward_labels() {
+        let source = "
+            main:   CALL draw
+                    JP main
+            draw:   LD I, ship + 1
+                    RET
+            ship:   DB 0x3C, 0x42
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.binary, [0x22, 0x04, 0x12, 0x00, 0xA2, 0x09, 0x00, 0xEE, 0x3C, 0x42]);
+        assert_eq!(assembly.labels["ship"], 0x208);
+    }
+
+    #[test]
+    fn symbol_map() {
+        let source = "
+            main: JP loop
+            zeta:
+            alpha:
+            loop: JP loop
+        ";
+        assert_eq!(assemble(source).unwrap().symbol_map(), "0x0200 main\n0x0202 alpha\n0x0202 loop\n0x0202 zeta\n");
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.asm"), "main: CALL draw\nINCLUDE \"lib/draw.asm\"\nJP main\n").unwrap();
+        fs::write(dir.join("lib/draw.asm"), "draw: RET\nINCLUDE \"more.asm\"\n").unwrap();
+        fs::write(dir.join("lib/more.asm"), "; empty so far\n").unwrap();
+        let assembly = assemble_file(&dir.join("main.asm")).unwrap();
+        assert_eq!(assembly.binary, [0x22, 0x02, 0x00, 0xEE, 0x12, 0x00]);
+
+        // errors name the included file, includes are relative to the file including them
+        fs::write(dir.join("lib/more.asm"), "; a comment\nLD V0, nowhere\n").unwrap();
+        let err = assemble_file(&dir.join("main.asm")).err().unwrap();
+        assert_eq!(err.line, 2);
+        assert!(err.file.ends_with("more.asm"), "{}", err.file);
+        assert_eq!(err.message, "unknown symbol nowhere");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("CLS\n\nJP 0x1000"), (3, "4096 is out of range, at most 0xFFF is allowed".to_string()));
+        assert_eq!(error("CLS\nFOO V1"), (2, "unknown instruction FOO".to_string()));
+        assert_eq!(error("ADD V1"), (1, "wrong operands for ADD".to_string()));
+        assert_eq!(error("LD V0, V1, V2"), (1, "wrong operands for LD".to_string()));
+        assert_eq!(error("LD V0, 256"), (1, "256 doesn't fit into a byte".to_string()));
+        assert_eq!(error("a: CLS\na: CLS"), (2, "a is already defined".to_string()));
+        assert_eq!(error("v1: CLS"), (1, "v1 can't be used as a name".to_string()));
+        assert_eq!(error("CLS\nLD V0, 1 +"), (2, "missing value in 1 +".to_string()));
+        assert_eq!(error("INCLUDE missing.asm"), (1, "INCLUDE needs a quoted path".to_string()));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PITCH: u8 = 64;
+
+/// AudioPattern is XO-CHIP's 1-bit audio buffer. While the buzzer sounds, the 128 bits of
+/// `buffer` are played in a loop, most significant bit first, at a rate set by `pitch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub buffer: [u8; 16],
+    pub pitch: u8,
+}
+
+/// the power-on state, an empty buffer at the default pitch
+impl Default for AudioPattern {
+    fn default() -> AudioPattern {
+        AudioPattern { buffer: [0; 16], pitch: DEFAULT_PITCH }
+    }
+}
+
+impl AudioPattern {
+    /// playback rate in bits per second, 4000 at the default pitch of 64
+    pub fn rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// the bit played at position `index`, wrapping around after 128 bits
+    pub fn bit(&self, index: usize) -> bool {
+        let index = index % 128;
+        self.buffer[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+/// AudioSink receives the state of the buzzer. The CPU calls `set_tone` once per emulated
+/// frame with whether the sound timer is non-zero during that frame, implementations are
+/// expected to only react to changes.
+pub trait AudioSink: Send {
+    fn set_tone(&mut self, on: bool);
+
+    /// set_pattern is called when an XO-CHIP program changes the pattern buffer or the pitch,
+    /// and with None when the machine goes back to a state without a pattern, e.g. after a
+    /// reset. Sinks that don't support patterns keep playing their plain tone.
+    fn set_pattern(&mut self, _pattern: Option<AudioPattern>) {}
+}
+
+/// NullSink discards the buzzer state, it is the default sink of a CPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn set_tone(&mut self, _on: bool) {}
+}
+
+/// RecordingSink remembers the buzzer state of every frame. Clones share the same recording,
+/// so a clone can be handed to the CPU and the original inspected afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    frames: Arc<Mutex<Vec<bool>>>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> RecordingSink {
+        RecordingSink::default()
+    }
+
+    /// the buzzer state of each frame, oldest first
+    pub fn frames(&self) -> Vec<bool> {
+        self.frames.lock().unwrap().clone()
+    }
+
+    /// number of frames during which the buzzer sounded
+    pub fn beeping_frames(&self) -> usize {
+        self.frames.lock().unwrap().iter().filter(|on| **on).count()
+    }
+
+    /// the pattern set last, None until the program set one or after it was cleared
+    pub fn pattern(&self) -> Option<AudioPattern> {
+        *self.pattern.lock().unwrap()
+    }
+}
+
+impl AudioSink for RecordingSink {
+    fn set_tone(&mut self, on: bool) {
+        self.frames.lock().unwrap().push(on);
+    }
+
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        *self.pattern.lock().unwrap() = pattern;
+    }
+}
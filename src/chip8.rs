@@ -1,21 +1,65 @@
-
-
-use std::{fmt, usize};
-use rand::Rng;
-use crate::frontend::Screen;
-use crate::frontend::ScreenTrait;
-use std::sync::mpsc;
+// register and instruction names follow the opcode tables, e.g. V, PC and draw_sprite_Dxyn
+#![allow(non_snake_case)]
+
+
+use crate::audio::{AudioPattern, AudioSink, NullSink};
+use crate::display::{Display, PLANES};
+use crate::error::{Chip8Error, ErrorKind, StateError};
+use crate::font::{self, FontStyle, BIG_FONT, BIG_FONT_START, FONT_START};
+use crate::instruction::{decode_long, Instruction};
+use crate::keypad::Keypad;
+use crate::platform::Platform;
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::rng::Rng;
+use crate::savestate;
+use crate::timer::{Timers, DEFAULT_INSTRUCTIONS_PER_FRAME};
+use std::ops::Range;
 
 // this is the entry address of chip8, it means CPU will fetch the very first instruction that is
 // stored at this address
-const PC_START: u16 = 0x200;
+pub const PC_START: u16 = 0x200;
 const SP_START: u8  = 0x10;
 
-pub struct Protocol {
-    pub cmd: String,
-    pub data: [[bool; 32]; 64],
+
+/// Snapshot is a copy of the complete machine state, see `CPU::snapshot` and `CPU::restore`.
+/// The quirks are included, the rest of the configuration (font, speed, audio sink) is not.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub V: [u8; 16],
+    pub I: u16,
+    pub PC: u16,
+    pub SP: u8,
+    pub timers: Timers,
+    pub stack: [u16; 16],
+    pub memory: Vec<u8>,
+    pub display: Display,
+    pub planes: u8,
+    pub audio_pattern: AudioPattern,
+    pub flags: [u8; 16],
+    pub halted: bool,
+    pub platform: Platform,
+    pub keypad: Keypad,
+    pub waiting_for_key: bool,
+    pub quirks: Quirks,
+    pub rng: Rng,
+    pub frame: u64,
 }
 
+/// MemoryAccess is the data memory the last executed instruction read and wrote, the
+/// instruction fetch itself doesn't count. Debuggers use it for watchpoints.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub read: Option<Range<usize>>,
+    pub written: Option<Range<usize>>,
+}
+
+// widen an access range to cover address
+fn extend_range(range: &mut Option<Range<usize>>, address: usize) {
+    *range = Some(match range.take() {
+        Some(range) => range.start.min(address)..range.end.max(address + 1),
+        None => address..address + 1,
+    });
+}
 
 // #[derive(Debug)]
 pub struct CPU {
@@ -23,501 +67,1083 @@ pub struct CPU {
     I: u16,        //  I to store memory address
     PC: u16,       //  Program Counter
     SP: u8,        //  Stack Pointer
-    DT: u8,        //  Delay Timer
-    ST: u8,        //  Sound Timer
+    timers: Timers, //  Delay Timer and Sound Timer
     stack: [u16; 16], //  Stack
-    memory: [u8; 4096], //  4k memory
-    screen: [[bool; 32]; 64],
-    tx: mpsc::Sender<Protocol>,
-}
-
-// #[derive(Debug)]
-pub struct Operand {
-    op_code: u8,
-    value: u16,
+    memory: Vec<u8>, //  4k memory, 64k on XO-CHIP
+    display: Display, //  64x32, or 128x64 in SCHIP hires mode
+    planes: u8,     //  XO-CHIP bitplanes selected by Fn01
+    audio_pattern: AudioPattern, //  XO-CHIP audio buffer and pitch
+    flags: [u8; 16], //  SCHIP RPL user flags
+    halted: bool,   //  00FD was executed
+    platform: Platform,
+    font: FontStyle,
+    keypad: Keypad,
+    waiting_for_key: bool,  //  Fx0A is blocking
+    vblank_wait: bool,      //  a draw ended the frame early, see Quirks::display_wait
+    cycles: u32,            //  instructions run in the current frame
+    access: MemoryAccess,   //  of the last instruction
+    quirks: Quirks,
+    rng: Rng,       //  Cxkk
+    rom_hash: u64,  //  of the loaded program, identifies the save states that belong to it
+    instructions_per_frame: u32,
+    frame: u64,     //  emulated frames run so far
+    audio: Box<dyn AudioSink>,
 }
 
-
 trait InstructionSet {
     fn increment_pc(&mut self);
-    fn increment_sp(&mut self);
-    fn decrement_sp(&mut self);
-
-    fn clear_display_00E0(&mut self);
-    fn return_from_subroutine_00EE(&mut self);
-    fn jump_to_location_1nnn(&mut self, address: u16);
-    fn call_subroutine_2nnn(&mut self, address: u16);
-    fn skip_if_eq_3xkk(&mut self, value: u16);
-    fn skip_if_neq_4xkk(&mut self, value: u16);
-    fn skip_if_reg_eq_5xy0(&mut self, value: u16);
-    fn load_value_to_reg_6xkk(&mut self, value: u16);
-    fn add_byte_7xkk(&mut self, value: u16);
-    fn store_reg_8xy0(&mut self, value: u16);
-    fn or_reg_8xy1(&mut self, value: u16);
-    fn and_reg_8xy2(&mut self, value: u16);
-    fn xor_reg_8xy3(&mut self, value: u16);
-    fn add_reg_8xy4(&mut self, value: u16);
-    fn sub_reg_8xy5(&mut self, value: u16);
-    fn shift_right_8xy6(&mut self, value: u16);
-    fn sub_reg_8xy7(&mut self, value: u16);
-    fn shift_left_8xyE(&mut self, value: u16);
-    fn skip_if_reg_neq_9xy0(&mut self, value: u16);
-    fn load_value_to_index_reg_Annn(&mut self, value: u16);
-    fn jump_to_location_Bnnn(&mut self, value: u16);
-    fn random_number_Cxkk(&mut self, value: u16);
-    fn draw_sprite_Dxyn(&mut self, value: u16);
-    fn skip_if_key_pressed_Ex9E(&mut self, value: u16);
-    fn skip_if_key_not_pressed_ExA1(&mut self, value: u16);
-    fn load_delay_timer_Fx07(&mut self, value: u16);
-    fn wait_for_key_press_Fx0A(&mut self, value: u16);
-    fn load_reg_to_delay_timer_Fx15(&mut self, value: u16);
-    fn load_reg_to_sound_timer_Fx18(&mut self, value: u16);
-    fn add_to_index_reg_Fx1E(&mut self, value: u16);
-    fn set_index_reg_to_sprite_Fx29(&mut self, value: u16);
-    fn store_binary_coded_decimal_Fx33(&mut self, value: u16);
-    fn store_registers_Fx55(&mut self, value: u16);
-    fn load_registers_Fx65(&mut self, value: u16);
-}
-
-impl Operand {
-    pub fn new(instruciton: u16) -> Operand {
-        Operand {
-            op_code: (((instruciton&0xF000) >> 12 ) as u8),
-            value: instruciton&0x0FFF,
-        }
-    }
-}
-
-impl fmt::Display for Operand {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "OPERAND(CODE: {:01X}, VAL: {:02X})", self.op_code, self.value)
-    }
+    fn skip_next_instruction(&mut self);
+    fn increment_sp(&mut self) -> Result<(), ErrorKind>;
+    fn decrement_sp(&mut self) -> Result<(), ErrorKind>;
+
+    fn sys_call_0nnn(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn scroll_down_00Cn(&mut self, n: u8) -> Result<(), ErrorKind>;
+    fn scroll_up_00Dn(&mut self, n: u8) -> Result<(), ErrorKind>;
+    fn clear_display_00E0(&mut self) -> Result<(), ErrorKind>;
+    fn return_from_subroutine_00EE(&mut self) -> Result<(), ErrorKind>;
+    fn scroll_right_00FB(&mut self) -> Result<(), ErrorKind>;
+    fn scroll_left_00FC(&mut self) -> Result<(), ErrorKind>;
+    fn exit_00FD(&mut self) -> Result<(), ErrorKind>;
+    fn low_resolution_00FE(&mut self) -> Result<(), ErrorKind>;
+    fn high_resolution_00FF(&mut self) -> Result<(), ErrorKind>;
+    fn jump_to_location_1nnn(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn call_subroutine_2nnn(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn skip_if_eq_3xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind>;
+    fn skip_if_neq_4xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind>;
+    fn skip_if_reg_eq_5xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn save_register_range_5xy2(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn load_register_range_5xy3(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn load_value_to_reg_6xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind>;
+    fn add_byte_7xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind>;
+    fn store_reg_8xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn or_reg_8xy1(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn and_reg_8xy2(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn xor_reg_8xy3(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn add_reg_8xy4(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn sub_reg_8xy5(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn shift_right_8xy6(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn sub_reg_8xy7(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn shift_left_8xyE(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn skip_if_reg_neq_9xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind>;
+    fn load_value_to_index_reg_Annn(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn jump_to_location_Bnnn(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn random_number_Cxkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind>;
+    fn load_long_index_reg_F000(&mut self, address: u16) -> Result<(), ErrorKind>;
+    fn select_planes_Fn01(&mut self, n: u8) -> Result<(), ErrorKind>;
+    fn load_audio_pattern_F002(&mut self) -> Result<(), ErrorKind>;
+    fn draw_sprite_Dxyn(&mut self, x: u8, y: u8, n: u8) -> Result<(), ErrorKind>;
+    fn skip_if_key_pressed_Ex9E(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn skip_if_key_not_pressed_ExA1(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn load_delay_timer_Fx07(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn wait_for_key_press_Fx0A(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn load_reg_to_delay_timer_Fx15(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn load_reg_to_sound_timer_Fx18(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn set_pitch_Fx3A(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn add_to_index_reg_Fx1E(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn set_index_reg_to_sprite_Fx29(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn set_index_reg_to_big_sprite_Fx30(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn store_binary_coded_decimal_Fx33(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn store_registers_Fx55(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn load_registers_Fx65(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn store_flags_Fx75(&mut self, x: u8) -> Result<(), ErrorKind>;
+    fn load_flags_Fx85(&mut self, x: u8) -> Result<(), ErrorKind>;
 }
 
 impl CPU {
     // create an instance of chip8 CPU
-    pub fn new(sender: mpsc::Sender<Protocol>) -> CPU {
-        CPU {
+    pub fn new(quirks: Quirks) -> CPU {
+        let mut cpu = CPU {
             V: [0; 16],
             I: 0,
             PC: PC_START,
             SP: SP_START,
-            DT: 0,
-            ST: 0,
+            timers: Timers::new(),
             stack: [0; 16],
-            memory: [0; 4096],
-            screen: [[false; 32];  64],
-            tx: sender,
+            memory: vec![0; Platform::default().memory_size()],
+            display: Display::new(),
+            planes: 1,
+            audio_pattern: AudioPattern::default(),
+            flags: [0; 16],
+            halted: false,
+            platform: Platform::default(),
+            font: FontStyle::default(),
+            keypad: Keypad::new(),
+            waiting_for_key: false,
+            vblank_wait: false,
+            cycles: 0,
+            access: MemoryAccess::default(),
+            quirks,
+            rng: Rng::from_entropy(),
+            rom_hash: savestate::rom_hash(&[]),
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            frame: 0,
+            audio: Box::new(NullSink),
+        };
+        cpu.load_font();
+        cpu
+    }
+
+    /// set_font selects the glyphs used by Fx29 and loads them into memory
+    pub fn set_font(&mut self, font: FontStyle) {
+        self.font = font;
+        self.load_font();
+    }
+
+    pub fn font(&self) -> FontStyle {
+        self.font
+    }
+
+    /// set_platform selects which instruction set extension is available
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.memory.resize(platform.memory_size(), 0);
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    /// whether the program exited with 00FD
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// the active resolution, 64x32 or 128x64
+    pub fn screen_size(&self) -> (usize, usize) {
+        self.display.size()
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.display.is_hires()
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// set_seed makes Cxkk repeatable, by default the generator is seeded randomly
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// the hash of the program last loaded with load_program
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn key_down(&mut self, key: u8) {
+        self.keypad.press(key);
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.keypad.release(key);
+    }
+
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// set_instructions_per_frame sets the CPU speed independently of the 60 Hz timers
+    pub fn set_instructions_per_frame(&mut self, count: u32) {
+        self.instructions_per_frame = count.max(1);
+    }
+
+    pub fn instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
+    }
+
+    /// set_audio_sink replaces the sink that is told when the buzzer sounds
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.audio = sink;
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
+    /// number of emulated frames run since the CPU was created
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    /// V0 to VF
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.V
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.V[x] = value;
+    }
+
+    pub fn index(&self) -> u16 {
+        self.I
+    }
+
+    pub fn set_index(&mut self, value: u16) {
+        self.I = value;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.PC
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.PC = value;
+    }
+
+    /// the stack pointer, 0x10 when the stack is empty, it counts down on every call
+    pub fn sp(&self) -> u8 {
+        self.SP
+    }
+
+    /// set_sp moves the stack pointer, values past the empty stack are clamped to 0x10
+    pub fn set_sp(&mut self, value: u8) {
+        self.SP = value.min(SP_START);
+    }
+
+    pub fn stack(&self) -> &[u16; 16] {
+        &self.stack
+    }
+
+    /// the return addresses of the active calls, innermost first
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack[(self.SP as usize).min(self.stack.len())..]
+    }
+
+    pub fn timers_mut(&mut self) -> &mut Timers {
+        &mut self.timers
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// the memory for debuggers to patch, the program sees changes with its next instruction
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// the data memory accessed by the last executed instruction
+    pub fn last_access(&self) -> &MemoryAccess {
+        &self.access
+    }
+
+    // copy the selected font into the interpreter area
+    fn load_font(&mut self) {
+        let start = FONT_START as usize;
+        let glyphs = self.font.glyphs();
+        self.memory[start..start + glyphs.len()].copy_from_slice(glyphs);
+        let start = BIG_FONT_START as usize;
+        self.memory[start..start + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
+    }
+
+    /// take_dirty returns the display rows changed since the last call (bit y for row y),
+    /// frontends use it to only redraw what changed
+    pub fn take_dirty(&mut self) -> u64 {
+        self.display.take_dirty()
+    }
+
+    /// snapshot copies the machine state, e.g. for save states
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            V: self.V,
+            I: self.I,
+            PC: self.PC,
+            SP: self.SP,
+            timers: self.timers,
+            stack: self.stack,
+            memory: self.memory.clone(),
+            display: self.display.clone(),
+            planes: self.planes,
+            audio_pattern: self.audio_pattern,
+            flags: self.flags,
+            halted: self.halted,
+            platform: self.platform,
+            keypad: self.keypad,
+            waiting_for_key: self.waiting_for_key,
+            quirks: self.quirks,
+            rng: self.rng,
+            frame: self.frame,
+        }
+    }
+
+    /// restore puts the machine back into the state of a snapshot
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.V = snapshot.V;
+        self.I = snapshot.I;
+        self.PC = snapshot.PC;
+        self.SP = snapshot.SP;
+        self.timers = snapshot.timers;
+        self.stack = snapshot.stack;
+        self.memory = snapshot.memory.clone();
+        self.display = snapshot.display.clone();
+        self.display.mark_all_dirty();
+        self.planes = snapshot.planes;
+        self.audio_pattern = snapshot.audio_pattern;
+        self.flags = snapshot.flags;
+        self.halted = snapshot.halted;
+        self.platform = snapshot.platform;
+        self.keypad = snapshot.keypad;
+        self.waiting_for_key = snapshot.waiting_for_key;
+        self.quirks = snapshot.quirks;
+        self.rng = snapshot.rng;
+        self.vblank_wait = false;
+        self.cycles = 0;
+        self.frame = snapshot.frame;
+        self.sync_audio_pattern();
+    }
+
+    // tell the sink about the pattern after it was replaced wholesale. An untouched pattern
+    // means the program never loaded one, the sink then plays its plain tone.
+    fn sync_audio_pattern(&mut self) {
+        let pattern = (self.audio_pattern != AudioPattern::default()).then_some(self.audio_pattern);
+        self.audio.set_pattern(pattern);
+    }
+
+    /// save_state serializes the machine into the versioned save state format, tagged with
+    /// the hash of the loaded program
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::encode(&self.snapshot(), self.rom_hash)
+    }
+
+    /// load_state restores a save state, refusing states of other programs and corrupt ones.
+    /// The machine is left untouched when it fails.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let (rom_hash, snapshot) = savestate::decode(data)?;
+        if rom_hash != self.rom_hash {
+            return Err(StateError::RomMismatch { expected: self.rom_hash, found: rom_hash });
         }
+        self.restore(&snapshot);
+        Ok(())
     }
 
     /// load_program loads binary to the memory of chip8 starting from PC_START
-    pub fn load_program(&mut self, program: Vec<u8>) {
+    pub fn load_program(&mut self, program: Vec<u8>) -> Result<(), Chip8Error> {
+        let max = self.memory.len() - PC_START as usize;
+        if program.len() > max {
+            return Err(Chip8Error::new(self.PC, 0, ErrorKind::RomTooLarge { size: program.len(), max }));
+        }
+
+        self.rom_hash = savestate::rom_hash(&program);
         // load program to memory
         for (i, byte) in program.iter().enumerate() {
             self.memory[PC_START as usize + i] = *byte;
         }
+        Ok(())
     }
 
-    // reset CPU
+    /// reset puts the machine into its power-on state, memory is cleared so the program has to
+    /// be loaded again. The configuration is kept, and so are the RPL flags, which survived a
+    /// restart on the HP-48 as well.
     pub fn reset(&mut self) {
+        self.V = [0; 16];
+        self.I = 0;
         self.PC = PC_START;
         self.SP = SP_START;
+        self.timers = Timers::new();
+        self.stack = [0; 16];
+        self.memory.iter_mut().for_each(|byte| *byte = 0);
+        self.display.set_hires(false);
+        self.planes = 1;
+        self.audio_pattern = AudioPattern::default();
+        self.halted = false;
+        self.keypad = Keypad::new();
+        self.waiting_for_key = false;
+        self.vblank_wait = false;
+        self.cycles = 0;
+        self.frame = 0;
+        self.load_font();
+        self.sync_audio_pattern();
+    }
+
+    /// run_frame executes one emulated 1/60 s frame: the instruction budget followed by a
+    /// single timer tick. It never looks at the wall clock.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        while !self.halted {
+            if self.cycle()? {
+                return Ok(());
+            }
+        }
+        self.end_frame();
+        Ok(())
     }
 
-    // run CPU
-    pub fn run(&mut self) {
-        loop {
-            let op = self.fetch();
-            self.execute(op);
+    /// cycle runs a single instruction as part of the current frame, the frame ends once its
+    /// instruction budget is used up or a draw waits for the next one. It returns whether the
+    /// frame ended, debuggers use it to run instruction by instruction at the right speed.
+    pub fn cycle(&mut self) -> Result<bool, Chip8Error> {
+        if self.cycles < self.instructions_per_frame {
+            self.step()?;
+            self.cycles += 1;
+        }
+        if self.cycles < self.instructions_per_frame && !self.vblank_wait {
+            return Ok(false);
         }
+        self.end_frame();
+        Ok(true)
     }
 
-    // fetch the operand from PC
-    pub fn fetch(&mut self) -> Operand {
-        let operand: Operand = Operand::new(((self.memory[self.PC as usize] as u16) << 8)  + (self.memory[self.PC as usize + 1] as u16));
-        operand
+    fn end_frame(&mut self) {
+        self.cycles = 0;
+        self.vblank_wait = false;
+        // the buzzer sounds for as many frames as ST held, so sample it before the tick
+        self.audio.set_tone(self.timers.sound_active());
+        self.timers.tick();
+        self.frame += 1;
     }
 
-    // execute operand
-    pub fn execute(&mut self, operand: Operand) {
-        let mut log = String::new();
-        log.push_str(&format!("PC:{:04X}, {}, ", self.PC, operand));
-        // println!("{}", log);
+    /// step fetches, decodes and executes a single instruction
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        let instruction = self.fetch_instruction()?;
+        self.execute(instruction)
+    }
 
-        match operand.op_code {
-            0x00 => {
-                match operand.value {
-                    0x0E0 => {
-                        self.clear_display_00E0(); 
-                    },
-                    0x0EE => {
-                        self.return_from_subroutine_00EE();
+    // fetch the raw opcode at PC
+    pub fn fetch(&self) -> Result<u16, Chip8Error> {
+        self.read_word(self.PC as usize).map_err(|kind| Chip8Error::new(self.PC, 0, kind))
+    }
 
-                    },
-                    _ => {
-                        panic!("Invalid operand!");
-                    }
-                }
-            },
-            0x01 => {
-                self.jump_to_location_1nnn(operand.value);
-            },
-            0x02 => {
-                self.call_subroutine_2nnn(operand.value);
-            },
-            0x03 => {
-                self.skip_if_eq_3xkk(operand.value);
-            },
-            0x04 => {
-                self.skip_if_neq_4xkk(operand.value);
-            },
-            0x06 => {
-                self.load_value_to_reg_6xkk(operand.value);
-            },
-            0x07 => {
-                self.add_byte_7xkk(operand.value);
-            },
-            0x0A => {
-                self.load_value_to_index_reg_Annn(operand.value);
-            },
-            0x0D => {
-                self.draw_sprite_Dxyn(operand.value);
-            },
-            0x0E => {
-                match operand.value & 0x00FF {
-                    0x9E => {
-                        self.skip_if_key_pressed_Ex9E(operand.value);
-                    },
-                    0xA1 => {
-                        self.skip_if_key_not_pressed_ExA1(operand.value);
-                    },
-                    _ => {
-                        panic!("Invalid operand!");
-                    }
-                }
-            },
-            0x0F => {
-                let sub_op_code = operand.value & 0x00FF;
-                match sub_op_code {
-                    0x07 => {
-                        self.load_delay_timer_Fx07(operand.value);
-                    },
-                    0x0A => {
-                        self.wait_for_key_press_Fx0A(operand.value);
-                    },
-                    0x15 => {
-                        self.load_reg_to_delay_timer_Fx15(operand.value);
-                    }
-                    0x18 => {
-                        self.load_reg_to_sound_timer_Fx18(operand.value);
-                    }
-                    0x1E => {
-                        self.add_to_index_reg_Fx1E(operand.value);
-                    }
-                    0x29 => {
-                        self.set_index_reg_to_sprite_Fx29(operand.value);
-                    }
-                    0x33 => {
-                        self.store_binary_coded_decimal_Fx33(operand.value);
-                    }
-                    0x55 => {
-                        self.store_registers_Fx55(operand.value);
-                    },
-                    0x65 => {
-                        self.load_registers_Fx65(operand.value);
-                    },
-                    _ => {
-                        panic!("Invalid operand!");
-                    }
-                }
+    // fetch and decode the instruction at PC, including the address word of F000 nnnn
+    pub fn fetch_instruction(&self) -> Result<Instruction, Chip8Error> {
+        let opcode = self.fetch()?;
+        if opcode != 0xF000 {
+            return Ok(decode_long(opcode, 0));
+        }
+        let next = self.read_word(self.PC as usize + 2).map_err(|kind| Chip8Error::new(self.PC, opcode, kind))?;
+        Ok(decode_long(opcode, next))
+    }
 
-            },
-            _ => {
-                panic!("Invalid operand!");
-            }
+    // execute a decoded instruction, errors are tagged with the PC and opcode it was fetched from
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        let pc = self.PC;
+        self.access = MemoryAccess::default();
+        if !self.platform.supports(&instruction) {
+            return Err(Chip8Error::new(pc, instruction.encode(), ErrorKind::UnknownOpcode));
         }
 
+        let result = match instruction {
+            Instruction::Sys { addr } => self.sys_call_0nnn(addr),
+            Instruction::ScrollDown { n } => self.scroll_down_00Cn(n),
+            Instruction::ScrollUp { n } => self.scroll_up_00Dn(n),
+            Instruction::ClearScreen => self.clear_display_00E0(),
+            Instruction::Return => self.return_from_subroutine_00EE(),
+            Instruction::ScrollRight => self.scroll_right_00FB(),
+            Instruction::ScrollLeft => self.scroll_left_00FC(),
+            Instruction::Exit => self.exit_00FD(),
+            Instruction::LowRes => self.low_resolution_00FE(),
+            Instruction::HighRes => self.high_resolution_00FF(),
+            Instruction::Jump { addr } => self.jump_to_location_1nnn(addr),
+            Instruction::Call { addr } => self.call_subroutine_2nnn(addr),
+            Instruction::SkipEqByte { x, kk } => self.skip_if_eq_3xkk(x, kk),
+            Instruction::SkipNeqByte { x, kk } => self.skip_if_neq_4xkk(x, kk),
+            Instruction::SkipEqRegs { x, y } => self.skip_if_reg_eq_5xy0(x, y),
+            Instruction::SaveRange { x, y } => self.save_register_range_5xy2(x, y),
+            Instruction::LoadRange { x, y } => self.load_register_range_5xy3(x, y),
+            Instruction::LoadByte { x, kk } => self.load_value_to_reg_6xkk(x, kk),
+            Instruction::AddByte { x, kk } => self.add_byte_7xkk(x, kk),
+            Instruction::LoadRegs { x, y } => self.store_reg_8xy0(x, y),
+            Instruction::Or { x, y } => self.or_reg_8xy1(x, y),
+            Instruction::And { x, y } => self.and_reg_8xy2(x, y),
+            Instruction::Xor { x, y } => self.xor_reg_8xy3(x, y),
+            Instruction::AddRegs { x, y } => self.add_reg_8xy4(x, y),
+            Instruction::Sub { x, y } => self.sub_reg_8xy5(x, y),
+            Instruction::ShiftRight { x, y } => self.shift_right_8xy6(x, y),
+            Instruction::SubN { x, y } => self.sub_reg_8xy7(x, y),
+            Instruction::ShiftLeft { x, y } => self.shift_left_8xyE(x, y),
+            Instruction::SkipNeqRegs { x, y } => self.skip_if_reg_neq_9xy0(x, y),
+            Instruction::LoadIndex { addr } => self.load_value_to_index_reg_Annn(addr),
+            Instruction::JumpOffset { addr } => self.jump_to_location_Bnnn(addr),
+            Instruction::Random { x, kk } => self.random_number_Cxkk(x, kk),
+            Instruction::LoadLongIndex { addr } => self.load_long_index_reg_F000(addr),
+            Instruction::SelectPlane { n } => self.select_planes_Fn01(n),
+            Instruction::LoadAudio => self.load_audio_pattern_F002(),
+            Instruction::Draw { x, y, n } => self.draw_sprite_Dxyn(x, y, n),
+            Instruction::SkipKeyPressed { x } => self.skip_if_key_pressed_Ex9E(x),
+            Instruction::SkipKeyNotPressed { x } => self.skip_if_key_not_pressed_ExA1(x),
+            Instruction::LoadDelayTimer { x } => self.load_delay_timer_Fx07(x),
+            Instruction::WaitKey { x } => self.wait_for_key_press_Fx0A(x),
+            Instruction::SetDelayTimer { x } => self.load_reg_to_delay_timer_Fx15(x),
+            Instruction::SetSoundTimer { x } => self.load_reg_to_sound_timer_Fx18(x),
+            Instruction::SetPitch { x } => self.set_pitch_Fx3A(x),
+            Instruction::AddIndex { x } => self.add_to_index_reg_Fx1E(x),
+            Instruction::LoadFont { x } => self.set_index_reg_to_sprite_Fx29(x),
+            Instruction::LoadBigFont { x } => self.set_index_reg_to_big_sprite_Fx30(x),
+            Instruction::StoreBcd { x } => self.store_binary_coded_decimal_Fx33(x),
+            Instruction::StoreRegisters { x } => self.store_registers_Fx55(x),
+            Instruction::LoadRegisters { x } => self.load_registers_Fx65(x),
+            Instruction::StoreFlags { x } => self.store_flags_Fx75(x),
+            Instruction::LoadFlags { x } => self.load_flags_Fx85(x),
+            Instruction::Unknown(_) => Err(ErrorKind::UnknownOpcode),
+        };
+
+        result.map_err(|kind| Chip8Error::new(pc, instruction.encode(), kind))
+    }
+
+    // the index_increment quirk decides what Fx55 and Fx65 leave in I
+    fn increment_index_after_load_store(&mut self, x: u8) {
+        match self.quirks.index_increment {
+            IndexIncrement::None => {},
+            IndexIncrement::X => self.I = self.I.wrapping_add(x as u16),
+            IndexIncrement::XPlusOne => self.I = self.I.wrapping_add(x as u16 + 1),
+        }
+    }
+
+    fn read_word(&self, address: usize) -> Result<u16, ErrorKind> {
+        let hi = self.read_memory(address)?;
+        let lo = self.read_memory(address + 1)?;
+        Ok(((hi as u16) << 8) | lo as u16)
+    }
+
+    fn read_memory(&self, address: usize) -> Result<u8, ErrorKind> {
+        self.memory.get(address).copied().ok_or(ErrorKind::MemoryOutOfBounds { address })
+    }
+
+    // read data for an instruction, unlike read_memory it is recorded in the last access
+    fn load_memory(&mut self, address: usize) -> Result<u8, ErrorKind> {
+        let value = self.read_memory(address)?;
+        extend_range(&mut self.access.read, address);
+        Ok(value)
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), ErrorKind> {
+        let cell = self.memory.get_mut(address).ok_or(ErrorKind::MemoryOutOfBounds { address })?;
+        *cell = value;
+        extend_range(&mut self.access.written, address);
+        Ok(())
     }
 
 }
 
 impl InstructionSet for CPU {
+    // the PC wraps around at the end of XO-CHIP's 64K address space
     fn increment_pc(&mut self) {
-        self.PC += 2;
+        self.PC = self.PC.wrapping_add(2);
+    }
+
+    fn skip_next_instruction(&mut self) {
+        // XO-CHIP skips over both words of F000 nnnn
+        let long = self.platform == Platform::XoChip && self.read_word(self.PC as usize) == Ok(0xF000);
+        self.PC = self.PC.wrapping_add(if long { 4 } else { 2 });
     }
 
-    fn increment_sp(&mut self) {
+    fn increment_sp(&mut self) -> Result<(), ErrorKind> {
+        // the stack grows downwards from SP_START
+        if self.SP == 0 {
+            return Err(ErrorKind::StackOverflow);
+        }
         self.SP -= 1;
+        Ok(())
     }
 
-    fn decrement_sp(&mut self) {
+    fn decrement_sp(&mut self) -> Result<(), ErrorKind> {
+        if self.SP >= SP_START {
+            return Err(ErrorKind::StackUnderflow);
+        }
         self.SP += 1;
+        Ok(())
     }
 
-    fn clear_display_00E0(&mut self) {
-        let cmd = Protocol {
-            cmd: String::from("CLS"),
-            data: [[false; 32]; 64],
-        };
-        self.tx.send(cmd).unwrap();
+    fn sys_call_0nnn(&mut self, _address: u16) -> Result<(), ErrorKind> {
+        // SYS addr jumps to a machine code routine on the original hardware, modern
+        // interpreters ignore it
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn scroll_down_00Cn(&mut self, n: u8) -> Result<(), ErrorKind> {
+        self.display.scroll(0, n as isize, self.planes);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn scroll_up_00Dn(&mut self, n: u8) -> Result<(), ErrorKind> {
+        self.display.scroll(0, -(n as isize), self.planes);
         self.increment_pc();
+        Ok(())
     }
 
-    fn return_from_subroutine_00EE(&mut self) {
+    fn clear_display_00E0(&mut self) -> Result<(), ErrorKind> {
+        // only the selected planes are cleared
+        self.display.clear(self.planes);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn return_from_subroutine_00EE(&mut self) -> Result<(), ErrorKind> {
+        if self.SP >= SP_START {
+            return Err(ErrorKind::StackUnderflow);
+        }
         self.PC = self.stack[self.SP as usize];
-        self.decrement_sp();
+        self.decrement_sp()?;
+        Ok(())
+    }
+
+    fn scroll_right_00FB(&mut self) -> Result<(), ErrorKind> {
+        self.display.scroll(4, 0, self.planes);
+        self.increment_pc();
+        Ok(())
     }
 
-    fn jump_to_location_1nnn(&mut self, address: u16) {
+    fn scroll_left_00FC(&mut self) -> Result<(), ErrorKind> {
+        self.display.scroll(-4, 0, self.planes);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn exit_00FD(&mut self) -> Result<(), ErrorKind> {
+        // PC stays on the exit instruction, the CPU doesn't run any further
+        self.halted = true;
+        Ok(())
+    }
+
+    fn low_resolution_00FE(&mut self) -> Result<(), ErrorKind> {
+        self.display.set_hires(false);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn high_resolution_00FF(&mut self) -> Result<(), ErrorKind> {
+        self.display.set_hires(true);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn jump_to_location_1nnn(&mut self, address: u16) -> Result<(), ErrorKind> {
         self.PC = address;
+        Ok(())
     }
 
-    fn call_subroutine_2nnn(&mut self, address: u16) {
-        self.increment_sp();
-        self.stack[self.SP as usize] = self.PC;
+    fn call_subroutine_2nnn(&mut self, address: u16) -> Result<(), ErrorKind> {
+        self.increment_sp()?;
+        // push the address of the next instruction so 00EE resumes after the call
+        self.stack[self.SP as usize] = self.PC.wrapping_add(2);
         self.PC = address;
+        Ok(())
     }
 
-    fn skip_if_eq_3xkk(&mut self, value: u16) {
+    fn skip_if_eq_3xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind> {
         self.increment_pc();
-        let kk = (value & 0x00FF) as u8;
-        let x = ((value & 0x0F00) >> 8) as u8;
         if self.V[x as usize] == kk {
-            self.increment_pc();
+            self.skip_next_instruction();
         }
+        Ok(())
     }
 
-    fn skip_if_neq_4xkk(&mut self, value: u16) {
+    fn skip_if_neq_4xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind> {
         self.increment_pc();
-        let kk = (value & 0x00FF) as u8;
-        let x = ((value & 0x0F00) >> 8) as u8;
         if self.V[x as usize] != kk {
-            self.increment_pc();
+            self.skip_next_instruction();
         }
+        Ok(())
     }
 
-    fn skip_if_reg_eq_5xy0(&mut self, value: u16) {
+    fn skip_if_reg_eq_5xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.increment_pc();
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
         if self.V[x as usize] == self.V[y as usize] {
-            self.increment_pc();
+            self.skip_next_instruction();
+        }
+        Ok(())
+    }
+
+    fn save_register_range_5xy2(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        // Vx..Vy are stored at I in the order given, x may be greater than y. I is unchanged.
+        let count = (x as isize - y as isize).unsigned_abs() + 1;
+        for i in 0..count {
+            let register = if x <= y { x as usize + i } else { x as usize - i };
+            self.write_memory(self.I as usize + i, self.V[register])?;
+        }
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn load_register_range_5xy3(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let count = (x as isize - y as isize).unsigned_abs() + 1;
+        for i in 0..count {
+            let register = if x <= y { x as usize + i } else { x as usize - i };
+            self.V[register] = self.load_memory(self.I as usize + i)?;
         }
+        self.increment_pc();
+        Ok(())
     }
 
-    fn load_value_to_reg_6xkk(&mut self, value: u16) {
-        let kk = (value & 0x00FF) as u8;
-        let x = ((value & 0x0F00) >> 8) as u8;
+    fn load_value_to_reg_6xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] = kk;
         self.increment_pc();
+        Ok(())
     }
 
-    fn add_byte_7xkk(&mut self, value: u16) {
-        let kk = (value & 0x00FF) as u8;
-        let x = ((value & 0x0F00) >> 8) as u8;
+    fn add_byte_7xkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] = self.V[x as usize].wrapping_add(kk);
         self.increment_pc();
+        Ok(())
     }
 
-    fn store_reg_8xy0(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
+    fn store_reg_8xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] = self.V[y as usize];
         self.increment_pc();
+        Ok(())
     }
     
-    fn or_reg_8xy1(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
+    fn or_reg_8xy1(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] |= self.V[y as usize];
+        if self.quirks.vf_reset {
+            self.V[0xF] = 0;
+        }
         self.increment_pc();
+        Ok(())
     }
 
-    fn and_reg_8xy2(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
+    fn and_reg_8xy2(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] &= self.V[y as usize];
+        if self.quirks.vf_reset {
+            self.V[0xF] = 0;
+        }
         self.increment_pc();
+        Ok(())
     }
 
-    fn xor_reg_8xy3(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
+    fn xor_reg_8xy3(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.V[x as usize] ^= self.V[y as usize];
+        if self.quirks.vf_reset {
+            self.V[0xF] = 0;
+        }
         self.increment_pc();
+        Ok(())
     }
 
-    fn add_reg_8xy4(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
-        self.V[0xF] = if self.V[x as usize] + self.V[y as usize] > 255 { 1 } else { 0 };
-        self.V[x as usize] += self.V[y as usize];
+    fn add_reg_8xy4(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let (sum, carry) = self.V[x as usize].overflowing_add(self.V[y as usize]);
+        self.V[x as usize] = sum;
+        self.V[0xF] = carry as u8;
         self.increment_pc();
+        Ok(())
     }
 
-    fn sub_reg_8xy5(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
-        self.V[0xF] = if self.V[x as usize] > self.V[y as usize] { 1 } else { 0 };
-        self.V[x as usize] -= self.V[y as usize];
+    fn sub_reg_8xy5(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let (diff, borrow) = self.V[x as usize].overflowing_sub(self.V[y as usize]);
+        self.V[x as usize] = diff;
+        self.V[0xF] = !borrow as u8;
         self.increment_pc();
+        Ok(())
     }
 
-    fn shift_right_8xy6(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.V[0xF] = self.V[x as usize] & 0x1;
-        self.V[x as usize] >>= 1;
+    fn shift_right_8xy6(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let value = if self.quirks.shift_uses_vy { self.V[y as usize] } else { self.V[x as usize] };
+        let flag = value & 0x1;
+        self.V[x as usize] = value >> 1;
+        self.V[0xF] = flag;
         self.increment_pc();
+        Ok(())
     }
 
-    fn sub_reg_8xy7(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
-        self.V[0xF] = if self.V[y as usize] > self.V[x as usize] { 1 } else { 0 };
-        self.V[x as usize] = self.V[y as usize] - self.V[x as usize];
+    fn sub_reg_8xy7(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let (diff, borrow) = self.V[y as usize].overflowing_sub(self.V[x as usize]);
+        self.V[x as usize] = diff;
+        self.V[0xF] = !borrow as u8;
         self.increment_pc();
+        Ok(())
     }
 
-    fn shift_left_8xyE(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.V[0xF] = self.V[x as usize] >> 7;
-        self.V[x as usize] <<= 1;
+    fn shift_left_8xyE(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
+        let value = if self.quirks.shift_uses_vy { self.V[y as usize] } else { self.V[x as usize] };
+        let flag = value >> 7;
+        self.V[x as usize] = value << 1;
+        self.V[0xF] = flag;
         self.increment_pc();
+        Ok(())
     }
 
-    fn skip_if_reg_neq_9xy0(&mut self, value: u16) {
+    fn skip_if_reg_neq_9xy0(&mut self, x: u8, y: u8) -> Result<(), ErrorKind> {
         self.increment_pc();
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
         if self.V[x as usize] != self.V[y as usize] {
-            self.increment_pc();
+            self.skip_next_instruction();
         }
+        Ok(())
     }
 
-    fn load_value_to_index_reg_Annn(&mut self, value: u16) {
-        self.I = value;
+    fn load_value_to_index_reg_Annn(&mut self, address: u16) -> Result<(), ErrorKind> {
+        self.I = address;
         self.increment_pc();
+        Ok(())
     }
 
-    fn jump_to_location_Bnnn(&mut self, value: u16) {
-        self.PC = value + self.V[0] as u16;
+    fn jump_to_location_Bnnn(&mut self, address: u16) -> Result<(), ErrorKind> {
+        // Bxnn jumps to xnn + Vx, x being the high nibble of the address
+        let offset = if self.quirks.jump_uses_vx { self.V[(address >> 8) as usize] } else { self.V[0] };
+        self.PC = address + offset as u16;
+        Ok(())
     }
 
-    fn random_number_Cxkk(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let kk = (value & 0x00FF) as u8;
-        let mut rng = rand::thread_rng();
-        let n: u8 = rng.gen_range(0..255);
+    fn random_number_Cxkk(&mut self, x: u8, kk: u8) -> Result<(), ErrorKind> {
+        let n = self.rng.next_u8();
         self.V[x as usize] = n & kk;
         self.increment_pc();
+        Ok(())
     }
 
-    fn draw_sprite_Dxyn(&mut self, value: u16) {
+    fn draw_sprite_Dxyn(&mut self, x: u8, y: u8, n: u8) -> Result<(), ErrorKind> {
         // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
         // The interpreter reads n bytes from memory, starting at the address stored in I. 
         // These bytes are then displayed as sprites on screen at coordinates (Vx, Vy). 
         // Sprites are XORed onto the existing screen. If this causes any pixels to be erased, 
         // VF is set to 1, otherwise it is set to 0. If the sprite is positioned so part of 
         // it is outside the coordinates of the display, it wraps around to the opposite 
-        // side of the screen. See instruction 8xy3 for more information on XOR, and 
-        // section 2.4, Display, for more information on the Chip-8 screen and sprites.
-        let x = ((value & 0x0F00) >> 8) as u8;
-        let y = ((value & 0x00F0) >> 4) as u8;
-        let n = ((value & 0x000F) >> 0) as u8;
-
-        // let sprite = Sprite {
-        //     x: self.V[x as usize] as i32,
-        //     y: self.V[y as usize] as i32,
-        //     data: self.memory[self.I as usize..self.I as usize + n as usize].to_vec(),
-        // };
-
-
-        self.V[0xF] = 0;
-        for i in 0..n {
-            let row = self.memory[(self.I + i as u16) as usize];
-            for j in 0..8 {
-                self.screen[(x + i) as usize][(y+j) as usize] ^= ((row >> (7 - j)) & 1) == 1;
-                if !self.screen[(x + i) as usize][(y+j) as usize] {
-                    self.V[0xF] |= 1;
+        // side of the screen, or is clipped if the clip_sprites quirk is set. See instruction
+        // 8xy3 for more information on XOR, and section 2.4, Display, for more information
+        // on the Chip-8 screen and sprites.
+        //
+        // On SCHIP Dxy0 draws a 16x16 sprite made of 32 bytes, two per row, in hires mode and
+        // an 8x16 sprite in lores mode. XO-CHIP draws 16x16 in both modes. On XO-CHIP the
+        // sprite is drawn on each selected plane, the data for plane 2 follows that of plane 1.
+        let origin_x = self.V[x as usize] as usize;
+        let origin_y = self.V[y as usize] as usize;
+        let (rows, sprite_width) = match self.platform {
+            Platform::Chip8 => (n as usize, 8),
+            _ if n != 0 => (n as usize, 8),
+            Platform::XoChip => (16, 16),
+            _ if self.display.is_hires() => (16, 16),
+            _ => (16, 8),
+        };
+        let bytes_per_row = sprite_width / 8;
+
+        let mut collision = false;
+        let mut address = self.I as usize;
+        for plane in 0..PLANES {
+            if self.planes & (1 << plane) == 0 {
+                continue;
+            }
+            let mut sprite = Vec::with_capacity(rows);
+            for _ in 0..rows {
+                let mut bits: u16 = 0;
+                for _ in 0..bytes_per_row {
+                    bits = (bits << 8) | self.load_memory(address)? as u16;
+                    address += 1;
                 }
+                sprite.push(bits);
             }
+            collision |= self.display.draw_sprite(plane, origin_x, origin_y, &sprite, sprite_width, self.quirks.clip_sprites);
         }
+        self.V[0xF] = collision as u8;
 
-        let cmd = Protocol {
-            cmd: String::from("DRAW"),
-            data: self.screen,
-        };
+        if self.quirks.display_wait {
+            self.vblank_wait = true;
+        }
+        self.increment_pc();
+        Ok(())
+    }
 
-        self.tx.send(cmd).unwrap();
+    fn skip_if_key_pressed_Ex9E(&mut self, x: u8) -> Result<(), ErrorKind> {
         self.increment_pc();
+        if self.keypad.is_pressed(self.V[x as usize]) {
+            self.skip_next_instruction();
+        }
+        Ok(())
+    }
+
+    fn skip_if_key_not_pressed_ExA1(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.increment_pc();
+        if !self.keypad.is_pressed(self.V[x as usize]) {
+            self.skip_next_instruction();
+        }
+        Ok(())
     }
 
-    fn skip_if_key_pressed_Ex9E(&mut self, _value: u16) {
-        // TODO: check keyboard
+    fn load_long_index_reg_F000(&mut self, address: u16) -> Result<(), ErrorKind> {
+        self.I = address;
+        // skip the instruction and its address word
+        self.PC = self.PC.wrapping_add(4);
+        Ok(())
+    }
 
+    fn select_planes_Fn01(&mut self, n: u8) -> Result<(), ErrorKind> {
+        self.planes = n & 0b11;
         self.increment_pc();
+        Ok(())
     }
 
-    fn skip_if_key_not_pressed_ExA1(&mut self, _value: u16) {
-        // TODO: check keyboard
+    fn load_audio_pattern_F002(&mut self) -> Result<(), ErrorKind> {
+        for i in 0..self.audio_pattern.buffer.len() {
+            self.audio_pattern.buffer[i] = self.load_memory(self.I as usize + i)?;
+        }
+        self.audio.set_pattern(Some(self.audio_pattern));
+        self.increment_pc();
+        Ok(())
+    }
 
+    fn load_delay_timer_Fx07(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.V[x as usize] = self.timers.delay;
         self.increment_pc();
+        Ok(())
     }
 
-    fn load_delay_timer_Fx07(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.V[x as usize] = self.DT;
+    fn wait_for_key_press_Fx0A(&mut self, x: u8) -> Result<(), ErrorKind> {
+        // PC is left untouched until a key goes down and back up, so the instruction keeps
+        // being re-executed while waiting
+        if !self.waiting_for_key {
+            self.waiting_for_key = true;
+            self.keypad.clear_latches();
+        }
+        if let Some(key) = self.keypad.take_released() {
+            self.V[x as usize] = key;
+            self.waiting_for_key = false;
+            self.increment_pc();
+        }
+        Ok(())
+    }
+
+    fn load_reg_to_delay_timer_Fx15(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.timers.delay = self.V[x as usize];
         self.increment_pc();
+        Ok(())
     }
 
-    fn wait_for_key_press_Fx0A(&mut self, _value: u16) {
-        // TODO: check keyboard
+    fn load_reg_to_sound_timer_Fx18(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.timers.sound = self.V[x as usize];
         self.increment_pc();
+        Ok(())
     }
 
-    fn load_reg_to_delay_timer_Fx15(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.DT = self.V[x as usize];
+    fn set_pitch_Fx3A(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.audio_pattern.pitch = self.V[x as usize];
+        self.audio.set_pattern(Some(self.audio_pattern));
         self.increment_pc();
+        Ok(())
     }
 
-    fn load_reg_to_sound_timer_Fx18(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.ST = self.V[x as usize];
+    fn add_to_index_reg_Fx1E(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.I = self.I.wrapping_add(self.V[x as usize] as u16);
         self.increment_pc();
+        Ok(())
     }
 
-    fn add_to_index_reg_Fx1E(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.I += self.V[x as usize] as u16;
+    fn set_index_reg_to_sprite_Fx29(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.I = font::glyph_address(self.V[x as usize]);
         self.increment_pc();
+        Ok(())
     }
 
-    fn set_index_reg_to_sprite_Fx29(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
-        self.I = self.V[x as usize] as u16;
+    fn set_index_reg_to_big_sprite_Fx30(&mut self, x: u8) -> Result<(), ErrorKind> {
+        self.I = font::big_glyph_address(self.V[x as usize]);
         self.increment_pc();
+        Ok(())
     }
 
-    fn store_binary_coded_decimal_Fx33(&mut self, value: u16) {
-        // TODO: fix me
+    fn store_binary_coded_decimal_Fx33(&mut self, x: u8) -> Result<(), ErrorKind> {
+        let vx = self.V[x as usize];
+        self.write_memory(self.I as usize, vx / 100)?;
+        self.write_memory(self.I as usize + 1, (vx / 10) % 10)?;
+        self.write_memory(self.I as usize + 2, vx % 10)?;
         self.increment_pc();
+        Ok(())
     }
 
-    fn store_registers_Fx55(&mut self, value: u16) {
-        // TODO: fix me
+    fn store_registers_Fx55(&mut self, x: u8) -> Result<(), ErrorKind> {
+        for i in 0..x+1 {
+            self.write_memory(self.I as usize + i as usize, self.V[i as usize])?;
+        }
+        self.increment_index_after_load_store(x);
         self.increment_pc();
+        Ok(())
     }
 
-    fn load_registers_Fx65(&mut self, value: u16) {
-        let x = ((value & 0x0F00) >> 8) as u8;
+    fn load_registers_Fx65(&mut self, x: u8) -> Result<(), ErrorKind> {
         for i in 0..x+1 {
-            self.V[i as usize] = self.memory[self.I as usize + i as usize];
+            self.V[i as usize] = self.load_memory(self.I as usize + i as usize)?;
         }
-        self.I += (x+1) as u16;
+        self.increment_index_after_load_store(x);
+        self.increment_pc();
+        Ok(())
+    }
+
+    fn store_flags_Fx75(&mut self, x: u8) -> Result<(), ErrorKind> {
+        // the HP-48 had 8 RPL user flags, XO-CHIP extends them to 16
+        let count = x as usize + 1;
+        self.flags[..count].copy_from_slice(&self.V[..count]);
         self.increment_pc();
+        Ok(())
+    }
+
+    fn load_flags_Fx85(&mut self, x: u8) -> Result<(), ErrorKind> {
+        let count = x as usize + 1;
+        self.V[..count].copy_from_slice(&self.flags[..count]);
+        self.increment_pc();
+        Ok(())
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::RecordingSink;
+
+    // 200: i := 206, 202: audio, 204: jump 204, 206: the pattern
+    const PATTERN_PROGRAM: [u8; 22] = [
+        0xA2, 0x06, 0xF0, 0x02, 0x12, 0x04, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0,
+        0xF0, 0xF0, 0xF0, 0xF0,
+    ];
+
+    fn cpu(platform: Platform, program: &[u8]) -> (CPU, RecordingSink) {
+        let mut cpu = CPU::new(platform.quirks());
+        cpu.set_platform(platform);
+        let sink = RecordingSink::new();
+        cpu.set_audio_sink(Box::new(sink.clone()));
+        cpu.load_program(program.to_vec()).unwrap();
+        (cpu, sink)
+    }
+
+    #[test]
+    fn restore_sets_audio_pattern() {
+        let (mut cpu, sink) = cpu(Platform::XoChip, &PATTERN_PROGRAM);
+        let before = cpu.snapshot();
+        cpu.run_frame().unwrap();
+        let pattern = AudioPattern { buffer: [0xF0; 16], ..AudioPattern::default() };
+        assert_eq!(sink.pattern(), Some(pattern));
+        let after = cpu.snapshot();
+
+        // back before F002 the plain tone plays again
+        cpu.restore(&before);
+        assert_eq!(sink.pattern(), None);
+        cpu.restore(&after);
+        assert_eq!(sink.pattern(), Some(pattern));
+    }
+
+    #[test]
+    fn reset_clears_audio_pattern() {
+        let (mut cpu, sink) = cpu(Platform::XoChip, &PATTERN_PROGRAM);
+        cpu.run_frame().unwrap();
+        assert!(sink.pattern().is_some());
+        cpu.reset();
+        assert_eq!(sink.pattern(), None);
+    }
+
+    #[test]
+    fn set_sp_stays_on_the_stack() {
+        // 200: call 200
+        let (mut cpu, _) = cpu(Platform::Chip8, &[0x22, 0x00]);
+        cpu.set_sp(0x11);
+        assert_eq!(cpu.sp(), 0x10);
+        cpu.set_sp(0xFF);
+        let err = (0..10).find_map(|_| cpu.run_frame().err()).unwrap();
+        assert_eq!(err.kind, ErrorKind::StackOverflow);
+        assert_eq!(cpu.call_stack(), [0x202; 16]);
+    }
+
+    #[test]
+    fn load_state_keeps_the_plain_tone() {
+        // 200: jump 200
+        let (mut cpu, sink) = cpu(Platform::Chip8, &[0x12, 0x00]);
+        cpu.run_frame().unwrap();
+        let state = cpu.save_state();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(sink.pattern(), None);
+    }
+}
@@ -0,0 +1,598 @@
+// a Debug Adapter Protocol server, see https://microsoft.github.io/debug-adapter-protocol/.
+// Messages are JSON objects preceded by a `Content-Length: n` header and a blank line, the
+// client sends requests, the server answers each with a response and sends events on its own.
+//
+// CHIP-8 programs have no source, so the program is presented as a listing with one line per
+// word from 0x200 onwards: line n shows address 0x200 + 2 * (n - 1). Breakpoints can be set on
+// its lines, or by address as instruction breakpoints.
+
+use crate::debugger::{Debugger, Register, Resume, StopReason};
+use crate::octo::compile;
+use crate::protocol::{ChannelSink, RunnerLink};
+use crate::repl::parse_number;
+use crate::timer::{FrameLimiter, TIMER_HZ};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const PROGRAM_START: u16 = 0x200;
+const THREAD_ID: i64 = 1;
+// variablesReference values, memory pages of 256 bytes are MEMORY_PAGE + page
+const REGISTERS: i64 = 1;
+const STACK: i64 = 2;
+const MEMORY: i64 = 3;
+const MEMORY_PAGE: i64 = 0x1000;
+const LISTING: i64 = 1;     //  the sourceReference of the listing
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// DapServer lets an editor like VS Code debug a ROM. It waits for a `launch` request with
+/// the `program` to run (and optionally `platform` and `stopOnEntry`), then supports line and
+/// instruction breakpoints, stack traces from the CHIP-8 stack, registers, the stack and
+/// memory as variables, stepping, continue and pause. The program runs at 60 frames per second
+/// and is shown through the RunnerLink.
+pub struct DapServer {
+    debugger: Debugger,
+    seq: i64,
+    program: String,            //  path of the launched ROM
+    program_len: usize,
+    stop_on_entry: bool,
+    line_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+}
+
+impl DapServer {
+    /// new wraps a debugger, `launch` loads the program into its CPU
+    pub fn new(debugger: Debugger) -> DapServer {
+        DapServer {
+            debugger,
+            seq: 0,
+            program: String::new(),
+            program_len: 0,
+            stop_on_entry: false,
+            line_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// serve talks to the client until it disconnects or goes away, or the frontend on the
+    /// other end of `link` quits. Requests are read from `input` on a thread of their own, a
+    /// message longer than 1 MiB ends the session with an `InvalidData` error.
+    pub fn serve(&mut self, input: impl Read + Send + 'static, mut output: impl Write, mut link: RunnerLink) -> io::Result<()> {
+        let (request_tx, requests) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(error) = read_messages(input, &request_tx) {
+                let _ = request_tx.send(Err(error));
+            }
+        });
+
+        let sink = ChannelSink::new(link.events.clone());
+        self.debugger.cpu_mut().set_audio_sink(Box::new(sink));
+        let mut limiter = FrameLimiter::new(TIMER_HZ);
+
+        loop {
+            if !link.apply_keys(self.debugger.cpu_mut()) {
+                return Ok(());
+            }
+
+            // requests are answered while the program runs, too, e.g. pause
+            let timeout = if self.debugger.is_running() { Duration::ZERO } else { Duration::from_millis(16) };
+            match requests.recv_timeout(timeout) {
+                Ok(request) => {
+                    let request = request?;
+                    let was_running = self.debugger.is_running();
+                    if !self.handle(&request, &mut output)? {
+                        return Ok(());
+                    }
+                    if !was_running && self.debugger.is_running() {
+                        limiter = FrameLimiter::new(TIMER_HZ);
+                    }
+                    // e.g. a launch cleared the display
+                    link.publish(self.debugger.cpu_mut());
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            if self.debugger.is_running() {
+                let stop = self.debugger.run_frame();
+                if self.debugger.cpu().display().dirty_rows() != 0 {
+                    link.publish(self.debugger.cpu_mut());
+                }
+                if let Some(reason) = stop {
+                    self.report_stop(&reason, &mut output)?;
+                }
+                limiter.wait();
+            }
+        }
+    }
+
+    // answer a request, false once the session is over
+    fn handle(&mut self, request: &Value, output: &mut impl Write) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsSetVariable": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsTerminateRequest": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.debugger.resume(Resume::Continue);
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY, "expensive": true },
+            ] })),
+            "variables" => Ok(self.variables(args["variablesReference"].as_i64().unwrap_or_default())),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => self.evaluate(args),
+            "source" => Ok(json!({ "content": self.listing() })),
+            "readMemory" => self.read_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => self.resume(Resume::Continue).map(|_| json!({ "allThreadsContinued": true })),
+            "next" => self.resume(Resume::StepOver),
+            "stepIn" => self.resume(Resume::Step),
+            "stepOut" => self.resume(Resume::StepOut),
+            // a running program reports the stop once it stopped, a stopped one right away
+            "pause" => {
+                if self.debugger.is_running() {
+                    self.debugger.interrupt().trigger();
+                }
+                Ok(Value::Null)
+            }
+            "disconnect" | "terminate" => {
+                self.respond(output, request, Ok(Value::Null))?;
+                self.send_event(output, "terminated", json!({}))?;
+                return Ok(false);
+            }
+            _ => Err(format!("{} is not supported", command)),
+        };
+        let succeeded = result.is_ok();
+        self.respond(output, request, result)?;
+        // events that have to follow the response
+        match command {
+            "initialize" => self.send_event(output, "initialized", json!({}))?,
+            "configurationDone" if succeeded && self.stop_on_entry => {
+                self.send_event(output, "stopped", json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }))?
+            }
+            "pause" if !self.debugger.is_running() => {
+                self.send_event(output, "stopped", json!({ "reason": "pause", "threadId": THREAD_ID, "allThreadsStopped": true }))?
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("launch needs a program")?;
+        let platform = match args["platform"].as_str() {
+            Some(name) => name.parse()?,
+            None => self.debugger.cpu().platform(),
+        };
+        let mut rom = fs::read(program).map_err(|err| format!("can't read {}: {}", program, err))?;
+        // Octo sources are compiled first
+        if program.ends_with(".8o") {
+            rom = compile(&String::from_utf8_lossy(&rom)).map_err(|err| format!("{}:{}", program, err))?.binary;
+        }
+
+        let cpu = self.debugger.cpu_mut();
+        cpu.set_platform(platform);
+        cpu.set_quirks(platform.quirks());
+        cpu.reset();
+        self.program_len = rom.len();
+        cpu.load_program(rom).map_err(|err| err.to_string())?;
+        self.program = program.to_string();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(Value::Null)
+    }
+
+    fn resume(&mut self, how: Resume) -> Result<Value, String> {
+        self.debugger.resume(how);
+        Ok(Value::Null)
+    }
+
+    // breakpoints on lines of the listing replace the previous ones
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let lines: Vec<i64> = args["breakpoints"].as_array().into_iter().flatten().filter_map(|b| b["line"].as_i64()).collect();
+        self.line_breakpoints = lines.iter().filter_map(|line| line_address(*line)).collect();
+        self.sync_breakpoints();
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| json!({ "verified": line_address(*line).is_some(), "line": line }))
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        let mut breakpoints = Vec::new();
+        self.instruction_breakpoints.clear();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or_default();
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let address = parse_number(reference).ok().and_then(|address| (address as i64).checked_add(offset)).and_then(|address| u16::try_from(address).ok());
+            if let Some(address) = address {
+                self.instruction_breakpoints.insert(address);
+            }
+            breakpoints.push(json!({
+                "verified": address.is_some(),
+                "instructionReference": address.map(|address| format!("0x{:03X}", address)),
+            }));
+        }
+        self.sync_breakpoints();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn sync_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+        for address in self.line_breakpoints.iter().chain(&self.instruction_breakpoints) {
+            self.debugger.add_breakpoint(*address);
+        }
+    }
+
+    // frame 0 is at PC, the others at the return addresses on the stack
+    fn stack_trace(&self) -> Value {
+        let cpu = self.debugger.cpu();
+        let frames: Vec<Value> = std::iter::once(cpu.pc())
+            .chain(cpu.call_stack().iter().copied())
+            .enumerate()
+            .map(|(id, address)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("0x{:03X}", address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", address),
+                });
+                if let Some(line) = address_line(address) {
+                    frame["source"] = self.source();
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn source(&self) -> Value {
+        let name = std::path::Path::new(&self.program).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        json!({ "name": format!("{} (listing)", name), "sourceReference": LISTING })
+    }
+
+    fn variables(&self, reference: i64) -> Value {
+        let debugger = &self.debugger;
+        let cpu = debugger.cpu();
+        let variables: Vec<Value> = match reference {
+            REGISTERS => Register::all()
+                .map(|register| {
+                    let value = debugger.register(register);
+                    json!({ "name": register.to_string(), "value": format!("0x{:0width$X}", value, width = 2 * register.size()), "variablesReference": 0 })
+                })
+                .collect(),
+            STACK => cpu
+                .call_stack()
+                .iter()
+                .enumerate()
+                .map(|(depth, address)| json!({ "name": format!("#{}", depth + 1), "value": format!("0x{:03X}", address), "variablesReference": 0 }))
+                .collect(),
+            MEMORY => (0..cpu.memory().len() / 256)
+                .map(|page| json!({
+                    "name": format!("0x{:04X}", page * 256),
+                    "value": "",
+                    "variablesReference": MEMORY_PAGE + page as i64,
+                    "memoryReference": format!("0x{:04X}", page * 256),
+                }))
+                .collect(),
+            page if page >= MEMORY_PAGE => {
+                let start = usize::try_from(page - MEMORY_PAGE).ok().and_then(|page| page.checked_mul(256)).unwrap_or(usize::MAX);
+                let rows = cpu.memory().get(start..start.saturating_add(256)).unwrap_or_default();
+                rows.chunks(16)
+                    .enumerate()
+                    .map(|(row, bytes)| {
+                        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                        json!({ "name": format!("0x{:04X}", start + row * 16), "value": hex.join(" "), "variablesReference": 0 })
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        if args["variablesReference"].as_i64() != Some(REGISTERS) {
+            return Err("only registers can be changed".to_string());
+        }
+        let register: Register = args["name"].as_str().unwrap_or_default().parse()?;
+        let value = parse_number(args["value"].as_str().unwrap_or_default().trim())?;
+        if value >> (8 * register.size()) != 0 {
+            return Err(format!("{} doesn't fit into {}", value, register));
+        }
+        self.debugger.set_register(register, value as u16);
+        Ok(json!({ "value": format!("0x{:0width$X}", value, width = 2 * register.size()) }))
+    }
+
+    // registers by name, e.g. for hovers and the watch view
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let register: Register = args["expression"].as_str().unwrap_or_default().trim().parse()?;
+        let value = self.debugger.register(register);
+        Ok(json!({ "result": format!("0x{:0width$X}", value, width = 2 * register.size()), "variablesReference": 0 }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let start = (parse_number(reference)? as i64).checked_add(args["offset"].as_i64().unwrap_or(0)).ok_or("offset out of range")?;
+        let memory = self.debugger.cpu().memory();
+        let count = usize::try_from(args["count"].as_u64().unwrap_or(0)).unwrap_or(usize::MAX).min(memory.len());
+        let start = start.clamp(0, memory.len() as i64) as usize;
+        let end = start.saturating_add(count).min(memory.len());
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(&memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    // every instruction is taken to be 2 bytes long when counting from the reference
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let reference = args["memoryReference"].as_str().unwrap_or_default();
+        let out_of_range = || "offset out of range".to_string();
+        let start = (parse_number(reference)? as i64)
+            .checked_add(args["offset"].as_i64().unwrap_or(0))
+            .and_then(|start| start.checked_add(args["instructionOffset"].as_i64().unwrap_or(0).checked_mul(2)?))
+            .ok_or_else(out_of_range)?;
+        // no more instructions than memory holds
+        let count = args["instructionCount"].as_i64().unwrap_or(0).clamp(0, self.debugger.cpu().memory().len() as i64 / 2);
+        start.checked_add(2 * count).ok_or_else(out_of_range)?;
+        let instructions: Vec<Value> = (0..count)
+            .map(|i| start + 2 * i)
+            .map(|address| match u16::try_from(address).ok().and_then(|address| Some((address, self.debugger.instruction_at(address)?))) {
+                Some((address, instruction)) => {
+                    let mut line = json!({
+                        "address": format!("0x{:03X}", address),
+                        "instructionBytes": format!("{:04X}", instruction.encode()),
+                        "instruction": instruction.to_string(),
+                    });
+                    if let Some(number) = address_line(address) {
+                        line["location"] = self.source();
+                        line["line"] = json!(number);
+                    }
+                    line
+                }
+                None => json!({ "address": format!("0x{:X}", address.max(0)), "instruction": "??", "presentationHint": "invalid" }),
+            })
+            .collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    // one line per word of the program
+    fn listing(&self) -> String {
+        let end = PROGRAM_START as usize + self.program_len;
+        (PROGRAM_START as usize..end)
+            .step_by(2)
+            .map(|address| match self.debugger.instruction_at(address as u16) {
+                Some(instruction) => format!("{:04X}: {:04X}  {}\n", address, instruction.encode(), instruction),
+                None => format!("{:04X}: ??\n", address),
+            })
+            .collect()
+    }
+
+    fn report_stop(&mut self, reason: &StopReason, output: &mut impl Write) -> io::Result<()> {
+        let (reason, text) = match reason {
+            StopReason::Stepped | StopReason::Reached(_) => ("step", None),
+            StopReason::Breakpoint(_) => ("breakpoint", None),
+            StopReason::Watchpoint { .. } | StopReason::RegisterChanged { .. } => ("data breakpoint", Some(reason.to_string())),
+            StopReason::Interrupted => ("pause", None),
+            StopReason::Error(err) => ("exception", Some(err.to_string())),
+            StopReason::Halted => {
+                self.send_event(output, "exited", json!({ "exitCode": 0 }))?;
+                return self.send_event(output, "terminated", json!({}));
+            }
+        };
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.send_event(output, "stopped", body)
+    }
+
+    fn respond(&mut self, output: &mut impl Write, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(output, response)
+    }
+
+    fn send_event(&mut self, output: &mut impl Write, event: &str, body: Value) -> io::Result<()> {
+        self.send(output, json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, output: &mut impl Write, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let text = message.to_string();
+        write!(output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        output.flush()
+    }
+}
+
+// parse the messages of the client, runs on its own thread until the input ends or a header
+// is out of bounds
+fn read_messages(input: impl Read, requests: &mpsc::Sender<io::Result<Value>>) -> io::Result<()> {
+    let mut input = BufReader::new(input);
+    loop {
+        let mut len = None;
+        loop {
+            let mut header = String::new();
+            match input.read_line(&mut header) {
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(_) => {}
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                len = value.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(len) = len else {
+            continue;
+        };
+        if len > MAX_MESSAGE_LEN {
+            let message = format!("message of {} bytes, at most {} are accepted", len, MAX_MESSAGE_LEN);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let mut body = vec![0; len];
+        if input.read_exact(&mut body).is_err() {
+            return Ok(());
+        }
+        // garbage is dropped, the client notices the missing response
+        if let Ok(message) = serde_json::from_slice::<Value>(&body) {
+            if requests.send(Ok(message)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn line_address(line: i64) -> Option<u16> {
+    let offset = line.checked_sub(1)?.checked_mul(2)?;
+    u16::try_from(offset.checked_add(PROGRAM_START as i64)?).ok().filter(|address| *address >= PROGRAM_START)
+}
+
+fn address_line(address: u16) -> Option<i64> {
+    if address < PROGRAM_START || !address.is_multiple_of(2) {
+        return None;
+    }
+    Some((address - PROGRAM_START) as i64 / 2 + 1)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::quirks::Quirks;
+    use crate::CPU;
+    use std::io::Cursor;
+
+    // run a session with the requests and return what the server sent
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let text = request.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+        }
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_program(vec![0x60, 0x05, 0x12, 0x00]).unwrap();
+        let (runner_link, _frontend_link) = protocol::link();
+        let mut output = Vec::new();
+        DapServer::new(Debugger::new(cpu)).serve(Cursor::new(input), &mut output, runner_link).unwrap();
+
+        let mut messages = Vec::new();
+        let mut rest = output.as_slice();
+        while let Some(start) = rest.windows(4).position(|window| window == b"\r\n\r\n") {
+            let header = std::str::from_utf8(&rest[..start]).unwrap();
+            let len: usize = header.strip_prefix("Content-Length: ").unwrap().parse().unwrap();
+            let body = &rest[start + 4..start + 4 + len];
+            messages.push(serde_json::from_slice(body).unwrap());
+            rest = &rest[start + 4 + len..];
+        }
+        messages
+    }
+
+    #[test]
+    fn pause_responds_before_stopping() {
+        let messages = session(&[json!({ "command": "pause", "arguments": { "threadId": THREAD_ID } })]);
+        assert_eq!(messages.len(), 2);
+        assert_eq!((&messages[0]["type"], &messages[0]["command"]), (&json!("response"), &json!("pause")));
+        assert_eq!((&messages[1]["event"], &messages[1]["body"]["reason"]), (&json!("stopped"), &json!("pause")));
+    }
+
+    #[test]
+    fn oversized_message() {
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX);
+        let (runner_link, _frontend_link) = protocol::link();
+        let mut server = DapServer::new(Debugger::new(CPU::new(Quirks::default())));
+        let error = server.serve(Cursor::new(input), Vec::new(), runner_link).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn client_values_out_of_range() {
+        let messages = session(&[
+            json!({ "command": "setBreakpoints", "arguments": { "breakpoints": [{ "line": i64::MIN }, { "line": i64::MAX }, { "line": 2 }] } }),
+            json!({ "command": "setInstructionBreakpoints", "arguments": { "breakpoints": [{ "instructionReference": "0xFFFFFFFF", "offset": i64::MAX }] } }),
+            json!({ "command": "readMemory", "arguments": { "memoryReference": "0xFFFFFFFF", "offset": i64::MAX, "count": 4 } }),
+            json!({ "command": "readMemory", "arguments": { "memoryReference": "0x200", "count": u64::MAX } }),
+            json!({ "command": "disassemble", "arguments": { "memoryReference": "0x200", "instructionOffset": i64::MIN, "instructionCount": 1 } }),
+            json!({ "command": "disassemble", "arguments": { "memoryReference": "0x200", "offset": i64::MAX, "instructionCount": i64::MAX } }),
+            json!({ "command": "disassemble", "arguments": { "memoryReference": "0x200", "instructionCount": i64::MAX } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": i64::MAX } }),
+        ]);
+        let success: Vec<bool> = messages.iter().map(|message| message["success"].as_bool().unwrap()).collect();
+        assert_eq!(success, [true, true, false, true, false, false, true, true]);
+
+        let lines: Vec<bool> = messages[0]["body"]["breakpoints"].as_array().unwrap().iter().map(|b| b["verified"].as_bool().unwrap()).collect();
+        assert_eq!(lines, [false, false, true]);
+        assert_eq!(messages[1]["body"]["breakpoints"][0]["verified"], false);
+        // the count is clamped to the size of memory, what lies past its end is unreadable
+        assert_eq!(messages[3]["body"]["unreadableBytes"], 0x200);
+        assert_eq!(messages[6]["body"]["instructions"].as_array().unwrap().len(), 0x800);
+        assert_eq!(messages[6]["body"]["instructions"][0]["instruction"], "LD V0, 0x05");
+    }
+}
@@ -0,0 +1,610 @@
+use crate::chip8::CPU;
+use crate::error::Chip8Error;
+use crate::instruction::{decode_long, Instruction};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A register a debugger can read, write and watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Register {
+    V(u8),
+    I,
+    PC,
+    SP,
+    /// the delay timer
+    DT,
+    /// the sound timer
+    ST,
+}
+
+impl Register {
+    /// every register in the order debuggers list them: V0-VF, I, PC, SP, DT, ST
+    pub fn all() -> impl Iterator<Item = Register> {
+        (0..16).map(Register::V).chain([Register::I, Register::PC, Register::SP, Register::DT, Register::ST])
+    }
+
+    /// size in bytes, I and PC hold 16 bits, the others 8
+    pub fn size(&self) -> usize {
+        match self {
+            Register::I | Register::PC => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::PC => write!(f, "PC"),
+            Register::SP => write!(f, "SP"),
+            Register::DT => write!(f, "DT"),
+            Register::ST => write!(f, "ST"),
+        }
+    }
+}
+
+// V0-VF, I, PC, SP, DT and ST, in any case
+impl FromStr for Register {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Register, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "I" => Ok(Register::I),
+            "PC" => Ok(Register::PC),
+            "SP" => Ok(Register::SP),
+            "DT" => Ok(Register::DT),
+            "ST" => Ok(Register::ST),
+            name => name
+                .strip_prefix('V')
+                .filter(|digit| digit.len() == 1)
+                .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+                .map(Register::V)
+                .ok_or_else(|| format!("unknown register {}", s)),
+        }
+    }
+}
+
+/// Which accesses of a watched address stop the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    /// reads and writes
+    Access,
+}
+
+/// Watchpoint watches the `len` bytes of memory starting at `address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u16,
+    pub len: u16,
+    pub kind: Watch,
+}
+
+/// Why a debugger stopped running the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// a step, step over or step out completed
+    Stepped,
+    /// run_to arrived at its address
+    Reached(u16),
+    /// the program arrived at a breakpoint, the instruction there hasn't run yet
+    Breakpoint(u16),
+    /// the instruction at `pc` read (`write` false) or wrote a watched address
+    Watchpoint { pc: u16, address: u16, write: bool },
+    /// the instruction at `pc` changed a watched register
+    RegisterChanged { pc: u16, register: Register, old: u16, new: u16 },
+    /// the program executed 00FD
+    Halted,
+    /// an instruction failed, the CPU is left as the failure found it
+    Error(Chip8Error),
+    /// Interrupt::trigger was called
+    Interrupted,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Stepped => write!(f, "stepped"),
+            StopReason::Reached(address) => write!(f, "reached 0x{:03X}", address),
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at 0x{:03X}", address),
+            StopReason::Watchpoint { pc, address, write } => {
+                let access = if *write { "wrote" } else { "read" };
+                write!(f, "0x{:03X} {} watched 0x{:03X}", pc, access, address)
+            }
+            StopReason::RegisterChanged { pc, register, old, new } => {
+                write!(f, "0x{:03X} changed {} from 0x{:02X} to 0x{:02X}", pc, register, old, new)
+            }
+            StopReason::Halted => write!(f, "halted"),
+            StopReason::Error(err) => write!(f, "{}", err),
+            StopReason::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+/// How a debugger resumes the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// run a single instruction
+    Step,
+    /// like Step, but a 2nnn call runs until the subroutine returned
+    StepOver,
+    /// run until the current subroutine returned, like Continue outside of one
+    StepOut,
+    /// run until PC is at the address
+    RunTo(u16),
+    /// run until a breakpoint, watchpoint or error
+    Continue,
+}
+
+/// Interrupt stops a running debugger from another thread, e.g. a socket reader.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    /// trigger makes the debugger stop with StopReason::Interrupted before its next instruction
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+// what the program is running towards
+#[derive(Debug, Clone, Copy)]
+enum Goal {
+    Step,
+    Return { pc: u16, sp: u8 },     //  a call returned to pc with the stack back at sp
+    Unwind { sp: u8 },              //  a return popped the frame at sp
+    Address(u16),
+    Forever,
+}
+
+/// Debugger wraps a CPU and runs it until a breakpoint, watchpoint, step or error stops it.
+///
+/// The blocking methods (`step`, `step_over`, `step_out`, `run_to`, `cont`) return when the
+/// program stops. Frontends that keep a window or a socket alive while the program runs
+/// call `resume` and then `run_frame` once per 60 Hz frame until it returns a StopReason.
+/// Timers tick and frames end as they would without a debugger.
+pub struct Debugger {
+    cpu: CPU,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    watched_registers: BTreeSet<Register>,
+    goal: Option<Goal>,     //  None while stopped
+    leaving: bool,          //  the first instruction after resume ignores its breakpoint
+    interrupt: Interrupt,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Debugger {
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            watched_registers: BTreeSet::new(),
+            goal: None,
+            leaving: false,
+            interrupt: Interrupt::default(),
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU {
+        self.cpu
+    }
+
+    /// a handle that stops the program from another thread
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// add_breakpoint stops the program before it executes the instruction at `address`,
+    /// false if there already is one
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// the breakpoint addresses in ascending order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// add_watchpoint stops the program after an instruction accessed the watched memory
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// watch_register stops the program after an instruction changed the register
+    pub fn watch_register(&mut self, register: Register) -> bool {
+        self.watched_registers.insert(register)
+    }
+
+    pub fn unwatch_register(&mut self, register: Register) -> bool {
+        self.watched_registers.remove(&register)
+    }
+
+    pub fn watched_registers(&self) -> impl Iterator<Item = Register> + '_ {
+        self.watched_registers.iter().copied()
+    }
+
+    pub fn register(&self, register: Register) -> u16 {
+        let cpu = &self.cpu;
+        match register {
+            Register::V(x) => cpu.registers()[x as usize] as u16,
+            Register::I => cpu.index(),
+            Register::PC => cpu.pc(),
+            Register::SP => cpu.sp() as u16,
+            Register::DT => cpu.timers().delay as u16,
+            Register::ST => cpu.timers().sound as u16,
+        }
+    }
+
+    /// set_register writes a register, values too big for it are truncated
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        let cpu = &mut self.cpu;
+        match register {
+            Register::V(x) => cpu.set_register(x as usize, value as u8),
+            Register::I => cpu.set_index(value),
+            Register::PC => cpu.set_pc(value),
+            // there are 16 stack slots, SP 0x10 is the empty stack
+            Register::SP => cpu.set_sp((value as u8).min(0x10)),
+            Register::DT => cpu.timers_mut().delay = value as u8,
+            Register::ST => cpu.timers_mut().sound = value as u8,
+        }
+    }
+
+    /// instruction_at decodes the instruction stored at `address`, None past the end of memory
+    pub fn instruction_at(&self, address: u16) -> Option<Instruction> {
+        let memory = self.cpu.memory();
+        let word = |at: usize| Some(u16::from_be_bytes([*memory.get(at)?, *memory.get(at + 1)?]));
+        let opcode = word(address as usize)?;
+        Some(decode_long(opcode, word(address as usize + 2).unwrap_or(0)))
+    }
+
+    /// whether the program is running towards a goal set by resume
+    pub fn is_running(&self) -> bool {
+        self.goal.is_some()
+    }
+
+    /// resume starts running the program, `run_frame` advances it
+    pub fn resume(&mut self, how: Resume) {
+        let cpu = &self.cpu;
+        let goal = match how {
+            Resume::Step => Goal::Step,
+            Resume::StepOver => match cpu.fetch_instruction() {
+                Ok(Instruction::Call { .. }) => Goal::Return { pc: cpu.pc().wrapping_add(2), sp: cpu.sp() },
+                _ => Goal::Step,
+            },
+            Resume::StepOut if cpu.call_stack().is_empty() => Goal::Forever,
+            Resume::StepOut => Goal::Unwind { sp: cpu.sp() },
+            Resume::RunTo(address) => Goal::Address(address),
+            Resume::Continue => Goal::Forever,
+        };
+        self.goal = Some(goal);
+        self.leaving = true;
+    }
+
+    /// run_frame runs the program until it stops or the current frame ends, None while it
+    /// keeps running. It does nothing while the program is stopped.
+    pub fn run_frame(&mut self) -> Option<StopReason> {
+        while self.goal.is_some() {
+            match self.advance() {
+                Ok(true) => return None,
+                Ok(false) => {}
+                Err(reason) => {
+                    self.goal = None;
+                    return Some(reason);
+                }
+            }
+        }
+        None
+    }
+
+    /// run resumes the program and blocks until it stops
+    pub fn run(&mut self, how: Resume) -> StopReason {
+        self.resume(how);
+        loop {
+            if let Some(reason) = self.run_frame() {
+                return reason;
+            }
+        }
+    }
+
+    /// step runs a single instruction
+    pub fn step(&mut self) -> StopReason {
+        self.run(Resume::Step)
+    }
+
+    /// step_over runs a single instruction, treating a 2nnn call as one
+    pub fn step_over(&mut self) -> StopReason {
+        self.run(Resume::StepOver)
+    }
+
+    /// step_out runs until the current subroutine returned
+    pub fn step_out(&mut self) -> StopReason {
+        self.run(Resume::StepOut)
+    }
+
+    /// run_to runs until PC is at `address`
+    pub fn run_to(&mut self, address: u16) -> StopReason {
+        self.run(Resume::RunTo(address))
+    }
+
+    /// cont runs until a breakpoint, watchpoint or error
+    pub fn cont(&mut self) -> StopReason {
+        self.run(Resume::Continue)
+    }
+
+    // run one instruction towards the goal, Ok(true) when the frame ended and Err when the
+    // program has to stop
+    fn advance(&mut self) -> Result<bool, StopReason> {
+        if self.interrupt.take() {
+            return Err(StopReason::Interrupted);
+        }
+        if self.cpu.is_halted() {
+            return Err(StopReason::Halted);
+        }
+        let pc = self.cpu.pc();
+        let leaving = std::mem::replace(&mut self.leaving, false);
+        if !leaving && self.breakpoints.contains(&pc) {
+            return Err(StopReason::Breakpoint(pc));
+        }
+
+        let before: Vec<(Register, u16)> = self.watched_registers.iter().map(|r| (*r, self.register(*r))).collect();
+        let frame_ended = self.cpu.cycle().map_err(StopReason::Error)?;
+
+        if let Some(reason) = self.check_watchpoints(pc) {
+            return Err(reason);
+        }
+        for (register, old) in before {
+            let new = self.register(register);
+            if new != old {
+                return Err(StopReason::RegisterChanged { pc, register, old, new });
+            }
+        }
+        if self.cpu.is_halted() {
+            return Err(StopReason::Halted);
+        }
+
+        let cpu = &self.cpu;
+        match self.goal {
+            Some(Goal::Step) => Err(StopReason::Stepped),
+            Some(Goal::Return { pc, sp }) if cpu.pc() == pc && cpu.sp() == sp => Err(StopReason::Stepped),
+            Some(Goal::Unwind { sp }) if cpu.sp() > sp => Err(StopReason::Stepped),
+            Some(Goal::Address(address)) if cpu.pc() == address => Err(StopReason::Reached(address)),
+            _ => Ok(frame_ended),
+        }
+    }
+
+    // the first watched address the last instruction accessed
+    fn check_watchpoints(&self, pc: u16) -> Option<StopReason> {
+        let access = self.cpu.last_access();
+        for watchpoint in &self.watchpoints {
+            let watched = watchpoint.address as usize..watchpoint.address as usize + watchpoint.len as usize;
+            let accesses = [(false, &access.read), (true, &access.written)];
+            for (write, range) in accesses {
+                let Some(range) = range else {
+                    continue;
+                };
+                let wanted = match watchpoint.kind {
+                    Watch::Read => !write,
+                    Watch::Write => write,
+                    Watch::Access => true,
+                };
+                let first = range.start.max(watched.start);
+                if wanted && first < range.end.min(watched.end) {
+                    return Some(StopReason::Watchpoint { pc, address: first as u16, write });
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+
+    // 200: v0 := 1         210: v2 += 1        216: v3 += 1
+    // 202: call 210        212: call 216       218: return
+    // 204: v1 := 5         214: return
+    // 206: i := 300
+    // 208: save v0
+    // 20A: load v0
+    // 20C: jump 20C
+    const PROGRAM: [u8; 26] = [
+        0x60, 0x01, 0x22, 0x10, 0x61, 0x05, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x65, 0x12, 0x0C, 0x00, 0x00, 0x72, 0x01, 0x22,
+        0x16, 0x00, 0xEE, 0x73, 0x01, 0x00, 0xEE,
+    ];
+
+    fn debugger(platform: Platform, program: &[u8]) -> Debugger {
+        let mut cpu = CPU::new(platform.quirks());
+        cpu.set_platform(platform);
+        cpu.load_program(program.to_vec()).unwrap();
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn step() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        assert_eq!(debugger.step(), StopReason::Stepped);
+        assert_eq!(debugger.register(Register::PC), 0x202);
+        // a call is entered
+        assert_eq!(debugger.step(), StopReason::Stepped);
+        assert_eq!((debugger.register(Register::PC), debugger.register(Register::SP)), (0x210, 0x0F));
+        assert!(!debugger.is_running());
+    }
+
+    #[test]
+    fn step_over() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        debugger.step();
+        // the whole call, nested calls included, counts as one instruction
+        assert_eq!(debugger.step_over(), StopReason::Stepped);
+        assert_eq!(debugger.register(Register::PC), 0x204);
+        assert_eq!((debugger.register(Register::V(2)), debugger.register(Register::V(3))), (1, 1));
+        // anything else is a plain step
+        assert_eq!(debugger.step_over(), StopReason::Stepped);
+        assert_eq!(debugger.register(Register::PC), 0x206);
+    }
+
+    #[test]
+    fn step_over_stops_at_breakpoints_inside() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        debugger.step();
+        debugger.add_breakpoint(0x216);
+        assert_eq!(debugger.step_over(), StopReason::Breakpoint(0x216));
+    }
+
+    #[test]
+    fn step_out() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        debugger.run_to(0x216);
+        assert_eq!(debugger.register(Register::SP), 0x0E);
+        // out of the inner subroutine into the outer one, then out of that
+        assert_eq!(debugger.step_out(), StopReason::Stepped);
+        assert_eq!((debugger.register(Register::PC), debugger.register(Register::SP)), (0x214, 0x0F));
+        assert_eq!(debugger.step_out(), StopReason::Stepped);
+        assert_eq!((debugger.register(Register::PC), debugger.register(Register::SP)), (0x204, 0x10));
+    }
+
+    #[test]
+    fn run_to() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        assert_eq!(debugger.run_to(0x216), StopReason::Reached(0x216));
+        assert_eq!(debugger.cpu().call_stack(), [0x214, 0x204]);
+        assert_eq!(debugger.run_to(0x208), StopReason::Reached(0x208));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        assert!(debugger.add_breakpoint(0x206));
+        assert!(!debugger.add_breakpoint(0x206));
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x206));
+        assert_eq!(debugger.register(Register::V(1)), 5);
+
+        // continuing leaves the breakpoint it stopped at, and comes back to one in a loop
+        debugger.add_breakpoint(0x20C);
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x20C));
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(0x20C));
+        assert_eq!(debugger.step(), StopReason::Stepped);
+        assert_eq!(debugger.register(Register::PC), 0x20C);
+
+        assert!(debugger.remove_breakpoint(0x20C));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [0x206]);
+        debugger.interrupt().trigger();
+        assert_eq!(debugger.cont(), StopReason::Interrupted);
+    }
+
+    #[test]
+    fn watchpoints() {
+        // SUPER-CHIP leaves I alone on save and load, so both touch 0x300
+        let mut debugger = debugger(Platform::SuperChip, &PROGRAM);
+        debugger.add_watchpoint(Watchpoint { address: 0x2FF, len: 2, kind: Watch::Write });
+        debugger.add_watchpoint(Watchpoint { address: 0x300, len: 1, kind: Watch::Read });
+        // the stop comes after the instruction ran
+        assert_eq!(debugger.cont(), StopReason::Watchpoint { pc: 0x208, address: 0x300, write: true });
+        assert_eq!(debugger.register(Register::PC), 0x20A);
+        assert_eq!(debugger.cont(), StopReason::Watchpoint { pc: 0x20A, address: 0x300, write: false });
+
+        // a watchpoint next to the accessed byte doesn't fire
+        let mut debugger = self::debugger(Platform::SuperChip, &PROGRAM);
+        debugger.add_watchpoint(Watchpoint { address: 0x301, len: 4, kind: Watch::Access });
+        assert_eq!(debugger.run_to(0x20C), StopReason::Reached(0x20C));
+        assert!(debugger.remove_watchpoint(&Watchpoint { address: 0x301, len: 4, kind: Watch::Access }));
+        assert!(debugger.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn register_watches() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        debugger.watch_register(Register::V(1));
+        debugger.watch_register(Register::I);
+        let changed = debugger.cont();
+        assert_eq!(changed, StopReason::RegisterChanged { pc: 0x204, register: Register::V(1), old: 0, new: 5 });
+        let changed = debugger.cont();
+        assert_eq!(changed, StopReason::RegisterChanged { pc: 0x206, register: Register::I, old: 0, new: 0x300 });
+        assert!(debugger.unwatch_register(Register::V(1)));
+        assert_eq!(debugger.watched_registers().collect::<Vec<_>>(), [Register::I]);
+    }
+
+    #[test]
+    fn halt_and_errors() {
+        // 200: exit
+        let mut debugger = self::debugger(Platform::SuperChip, &[0x00, 0xFD]);
+        assert_eq!(debugger.cont(), StopReason::Halted);
+        assert_eq!(debugger.step(), StopReason::Halted);
+
+        // 200: return with nothing to return to
+        let mut debugger = self::debugger(Platform::Chip8, &[0x00, 0xEE]);
+        assert!(matches!(debugger.cont(), StopReason::Error(_)));
+        assert_eq!(debugger.register(Register::PC), 0x200);
+    }
+
+    #[test]
+    fn run_frame() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        assert_eq!(debugger.run_frame(), None);
+        debugger.resume(Resume::Continue);
+        // the program ends in a loop, so every frame ends without a stop
+        for _ in 0..3 {
+            assert_eq!(debugger.run_frame(), None);
+            assert!(debugger.is_running());
+        }
+        assert_eq!(debugger.cpu().frame_count(), 3);
+        debugger.interrupt().trigger();
+        assert_eq!(debugger.run_frame(), Some(StopReason::Interrupted));
+        assert!(!debugger.is_running());
+    }
+
+    #[test]
+    fn registers() {
+        let mut debugger = debugger(Platform::Chip8, &PROGRAM);
+        debugger.set_register(Register::V(0xA), 0x1FF);
+        debugger.set_register(Register::DT, 30);
+        debugger.set_register(Register::PC, 0x210);
+        assert_eq!(debugger.register(Register::V(0xA)), 0xFF);
+        assert_eq!(debugger.register(Register::DT), 30);
+        assert_eq!(debugger.instruction_at(0x210), Some(Instruction::AddByte { x: 2, kk: 1 }));
+        assert_eq!("vA".parse(), Ok(Register::V(0xA)));
+        assert_eq!("pc".parse(), Ok(Register::PC));
+        assert!("v10".parse::<Register>().is_err());
+    }
+}
@@ -0,0 +1,378 @@
+// a disassembler for ROMs. Code is found by recursive descent: starting at PC_START it follows
+// every path through jumps, calls and skips, so whatever is never reached that way, usually
+// sprites, is listed as data. Targets of jumps and calls and addresses loaded into I get
+// labels, and the listing can be written in Cowgod's mnemonics or in Octo.
+
+use crate::chip8::PC_START;
+use crate::instruction::{decode_long, Instruction};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+const COMMENT_COLUMN: usize = 28;
+
+/// The syntax of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    /// the mnemonics of Cowgod's technical reference, as `Instruction` prints them
+    #[default]
+    Cowgod,
+    /// the statements of the Octo assembly language
+    Octo,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Syntax, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "cowgod" => Ok(Syntax::Cowgod),
+            "octo" => Ok(Syntax::Octo),
+            _ => Err(format!("unknown syntax {}", name)),
+        }
+    }
+}
+
+/// What a label marks, in order of precedence when an address is used in several ways.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LabelKind {
+    /// the target of a jump
+    Jump,
+    /// data loaded into I
+    Data,
+    /// the table of a Bnnn jump
+    Table,
+    /// the target of a call
+    Subroutine,
+    /// the entry point at PC_START
+    Main,
+}
+
+/// Disassembly is a ROM split into code and data.
+pub struct Disassembly {
+    rom: Vec<u8>,
+    code: Vec<bool>,    //  whether an instruction starts at each byte
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+/// disassemble finds the code in a ROM loaded at PC_START. Bytes that would be loaded past
+/// the end of the 64K address space are left out.
+pub fn disassemble(rom: &[u8]) -> Disassembly {
+    let rom = &rom[..rom.len().min(0x10000 - PC_START as usize)];
+    let mut disassembly = Disassembly { rom: rom.to_vec(), code: vec![false; rom.len()], labels: BTreeMap::new() };
+    disassembly.trace();
+    disassembly
+}
+
+impl Disassembly {
+    /// whether an instruction starts at the address
+    pub fn is_code(&self, address: u16) -> bool {
+        self.offset(address).is_some_and(|offset| self.code[offset])
+    }
+
+    /// the labelled addresses, in ascending order
+    pub fn labels(&self) -> impl Iterator<Item = (u16, LabelKind)> + '_ {
+        self.labels.iter().map(|(address, kind)| (*address, *kind))
+    }
+
+    /// label returns the name of the label at the address
+    pub fn label(&self, address: u16) -> Option<String> {
+        let kind = self.labels.get(&address)?;
+        Some(match kind {
+            LabelKind::Main => "main".to_string(),
+            LabelKind::Subroutine => format!("sub_{:03X}", address),
+            LabelKind::Table => format!("table_{:03X}", address),
+            LabelKind::Data => format!("data_{:03X}", address),
+            LabelKind::Jump => format!("label_{:03X}", address),
+        })
+    }
+
+    /// instruction returns the instruction starting at the address, if it is code
+    pub fn instruction(&self, address: u16) -> Option<Instruction> {
+        if !self.is_code(address) {
+            return None;
+        }
+        self.decode(address)
+    }
+
+    /// listing writes the ROM out in the syntax, code as instructions and data as one byte
+    /// per line with the bits drawn as a sprite row
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let comment = match syntax {
+            Syntax::Cowgod => ';',
+            Syntax::Octo => '#',
+        };
+        let mut text = String::new();
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let address = PC_START + offset as u16;
+            if let Some(label) = self.label(address) {
+                match syntax {
+                    Syntax::Cowgod => writeln!(text, "{}:", label).unwrap(),
+                    Syntax::Octo => writeln!(text, ": {}", label).unwrap(),
+                }
+            }
+            let (line, size) = match self.instruction(address) {
+                Some(instruction) => {
+                    let statement = match syntax {
+                        Syntax::Cowgod => self.cowgod(&instruction),
+                        Syntax::Octo => self.octo(&instruction),
+                    };
+                    let bytes: String = self.rom[offset..offset + instruction.size() as usize].iter().map(|byte| format!("{:02X}", byte)).collect();
+                    (format!("    {:<width$}{} {:03X}  {}", statement, comment, address, bytes, width = COMMENT_COLUMN), instruction.size() as usize)
+                }
+                None => {
+                    let byte = self.rom[offset];
+                    let statement = match syntax {
+                        Syntax::Cowgod => format!("DB 0x{:02X}", byte),
+                        Syntax::Octo => format!("0x{:02X}", byte),
+                    };
+                    (format!("    {:<width$}{} {:03X}  {}", statement, comment, address, sprite_row(byte), width = COMMENT_COLUMN), 1)
+                }
+            };
+            writeln!(text, "{}", line).unwrap();
+            offset += size;
+        }
+        text
+    }
+
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.checked_sub(PC_START)? as usize;
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    fn word(&self, address: u16) -> Option<u16> {
+        let offset = self.offset(address)?;
+        let bytes = self.rom.get(offset..offset + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn decode(&self, address: u16) -> Option<Instruction> {
+        let opcode = self.word(address)?;
+        let instruction = decode_long(opcode, self.word(address.wrapping_add(2)).unwrap_or(0));
+        // F000 without its address is cut off
+        if instruction.size() == 4 && self.word(address.wrapping_add(2)).is_none() {
+            return None;
+        }
+        Some(instruction)
+    }
+
+    fn label_at(&mut self, address: u16, kind: LabelKind) {
+        let entry = self.labels.entry(address).or_insert(kind);
+        *entry = (*entry).max(kind);
+    }
+
+    // follow every path from PC_START, stopping at returns, jumps and whatever doesn't decode
+    fn trace(&mut self) {
+        let mut pending = vec![PC_START];
+        let mut visited = vec![false; self.rom.len()];
+        if !self.rom.is_empty() {
+            self.labels.insert(PC_START, LabelKind::Main);
+        }
+        while let Some(address) = pending.pop() {
+            let Some(offset) = self.offset(address) else {
+                continue;
+            };
+            if visited[offset] {
+                continue;
+            }
+            let Some(instruction) = self.decode(address) else {
+                continue;
+            };
+            if let Instruction::Unknown(_) = instruction {
+                continue;
+            }
+            let size = instruction.size() as usize;
+            // an instruction overlapping one found before means the path went astray
+            if visited[offset..(offset + size).min(self.rom.len())].iter().any(|visited| *visited) {
+                continue;
+            }
+            visited[offset..(offset + size).min(self.rom.len())].iter_mut().for_each(|visited| *visited = true);
+            self.code[offset] = true;
+
+            let next = address.wrapping_add(size as u16);
+            match instruction {
+                Instruction::Jump { addr } => {
+                    self.label_at(addr, LabelKind::Jump);
+                    pending.push(addr);
+                }
+                Instruction::Call { addr } => {
+                    self.label_at(addr, LabelKind::Subroutine);
+                    pending.push(addr);
+                    pending.push(next);
+                }
+                // the target depends on V0, it is usually a table of jumps at the address
+                Instruction::JumpOffset { addr } => {
+                    self.label_at(addr, LabelKind::Table);
+                    pending.push(addr);
+                    let mut entry = addr.wrapping_add(2);
+                    while self.word(entry).is_some_and(|opcode| opcode & 0xF000 == 0x1000) {
+                        pending.push(entry);
+                        entry = entry.wrapping_add(2);
+                    }
+                }
+                Instruction::Return | Instruction::Exit => {}
+                Instruction::SkipEqByte { .. }
+                | Instruction::SkipNeqByte { .. }
+                | Instruction::SkipEqRegs { .. }
+                | Instruction::SkipNeqRegs { .. }
+                | Instruction::SkipKeyPressed { .. }
+                | Instruction::SkipKeyNotPressed { .. } => {
+                    pending.push(next);
+                    // skips jump over both words of F000 nnnn
+                    let skipped = if self.word(next) == Some(0xF000) { 4 } else { 2 };
+                    pending.push(next.wrapping_add(skipped));
+                }
+                Instruction::LoadIndex { addr } | Instruction::LoadLongIndex { addr } => {
+                    self.label_at(addr, LabelKind::Data);
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+        // labels only go where a line starts: instructions, and data not covered by code
+        let labels: Vec<u16> = self.labels.keys().copied().collect();
+        for address in labels {
+            let starts_line = match self.offset(address) {
+                Some(offset) => self.code[offset] || !visited[offset],
+                None => false,
+            };
+            if !starts_line {
+                self.labels.remove(&address);
+            }
+        }
+    }
+
+    // the operand of instructions that take an address, a label where there is one
+    fn target(&self, address: u16, digits: usize) -> String {
+        self.label(address).unwrap_or_else(|| format!("0x{:0digits$X}", address, digits = digits))
+    }
+
+    fn cowgod(&self, instruction: &Instruction) -> String {
+        match *instruction {
+            Instruction::Jump { addr } => format!("JP {}", self.target(addr, 3)),
+            Instruction::Call { addr } => format!("CALL {}", self.target(addr, 3)),
+            Instruction::LoadIndex { addr } => format!("LD I, {}", self.target(addr, 3)),
+            Instruction::JumpOffset { addr } => format!("JP V0, {}", self.target(addr, 3)),
+            Instruction::LoadLongIndex { addr } => format!("LD I, LONG {}", self.target(addr, 4)),
+            _ => instruction.to_string(),
+        }
+    }
+
+    fn octo(&self, instruction: &Instruction) -> String {
+        match *instruction {
+            Instruction::Sys { addr } => format!("0x{:02X} 0x{:02X}", addr >> 8, addr & 0xFF),
+            Instruction::ScrollDown { n } => format!("scroll-down {}", n),
+            Instruction::ScrollUp { n } => format!("scroll-up {}", n),
+            Instruction::ClearScreen => "clear".to_string(),
+            Instruction::Return => "return".to_string(),
+            Instruction::ScrollRight => "scroll-right".to_string(),
+            Instruction::ScrollLeft => "scroll-left".to_string(),
+            Instruction::Exit => "exit".to_string(),
+            Instruction::LowRes => "lores".to_string(),
+            Instruction::HighRes => "hires".to_string(),
+            Instruction::Jump { addr } => format!("jump {}", self.target(addr, 3)),
+            // a call is just the name of the subroutine, addresses need :call
+            Instruction::Call { addr } => match self.label(addr) {
+                Some(label) => label,
+                None => format!(":call 0x{:03X}", addr),
+            },
+            // Octo's conditions name when the next instruction runs, the opposite of the skip
+            Instruction::SkipEqByte { x, kk } => format!("if v{:x} != 0x{:02X} then", x, kk),
+            Instruction::SkipNeqByte { x, kk } => format!("if v{:x} == 0x{:02X} then", x, kk),
+            Instruction::SkipEqRegs { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::SkipNeqRegs { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::SkipKeyPressed { x } => format!("if v{:x} -key then", x),
+            Instruction::SkipKeyNotPressed { x } => format!("if v{:x} key then", x),
+            Instruction::SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            Instruction::LoadByte { x, kk } => format!("v{:x} := 0x{:02X}", x, kk),
+            Instruction::AddByte { x, kk } => format!("v{:x} += 0x{:02X}", x, kk),
+            Instruction::LoadRegs { x, y } => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::AddRegs { x, y } => format!("v{:x} += v{:x}", x, y),
+            Instruction::Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Instruction::ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::SubN { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Instruction::ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            Instruction::LoadIndex { addr } => format!("i := {}", self.target(addr, 3)),
+            Instruction::JumpOffset { addr } => format!("jump0 {}", self.target(addr, 3)),
+            Instruction::Random { x, kk } => format!("v{:x} := random 0x{:02X}", x, kk),
+            Instruction::LoadLongIndex { addr } => format!("i := long {}", self.target(addr, 4)),
+            Instruction::SelectPlane { n } => format!("plane {}", n),
+            Instruction::LoadAudio => "audio".to_string(),
+            Instruction::Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::LoadDelayTimer { x } => format!("v{:x} := delay", x),
+            Instruction::WaitKey { x } => format!("v{:x} := key", x),
+            Instruction::SetDelayTimer { x } => format!("delay := v{:x}", x),
+            Instruction::SetSoundTimer { x } => format!("buzzer := v{:x}", x),
+            Instruction::SetPitch { x } => format!("pitch := v{:x}", x),
+            Instruction::AddIndex { x } => format!("i += v{:x}", x),
+            Instruction::LoadFont { x } => format!("i := hex v{:x}", x),
+            Instruction::LoadBigFont { x } => format!("i := bighex v{:x}", x),
+            Instruction::StoreBcd { x } => format!("bcd v{:x}", x),
+            Instruction::StoreRegisters { x } => format!("save v{:x}", x),
+            Instruction::LoadRegisters { x } => format!("load v{:x}", x),
+            Instruction::StoreFlags { x } => format!("saveflags v{:x}", x),
+            Instruction::LoadFlags { x } => format!("loadflags v{:x}", x),
+            Instruction::Unknown(opcode) => format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF),
+        }
+    }
+}
+
+// a byte as a row of a sprite, # for set bits
+fn sprite_row(byte: u8) -> String {
+    (0..8).map(|bit| if byte & 0x80 >> bit != 0 { '#' } else { '.' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::octo::compile;
+
+    const ROM: &[u8] = include_bytes!("../tests/danm8ku.ch8");
+
+    #[test]
+    fn cowgod_listing_reassembles() {
+        let listing = disassemble(ROM).listing(Syntax::Cowgod);
+        assert_eq!(assemble(&listing).unwrap().binary, ROM);
+    }
+
+    #[test]
+    fn octo_listing_recompiles() {
+        let listing = disassemble(ROM).listing(Syntax::Octo);
+        assert_eq!(compile(&listing).unwrap().binary, ROM);
+    }
+
+    #[test]
+    fn code_and_data() {
+        // 200: I := 208, 202: sprite, 204: call 20A, 206: jump 206, 208: data, 20A: return
+        let rom = [0xA2, 0x08, 0xD0, 0x11, 0x22, 0x0A, 0x12, 0x06, 0xF0, 0x90, 0x00, 0xEE];
+        let disassembly = disassemble(&rom);
+        assert!(disassembly.is_code(0x206));
+        assert!(!disassembly.is_code(0x208));
+        assert!(disassembly.is_code(0x20A));
+        let labels: Vec<_> = disassembly.labels().collect();
+        assert_eq!(
+            labels,
+            [(0x200, LabelKind::Main), (0x206, LabelKind::Jump), (0x208, LabelKind::Data), (0x20A, LabelKind::Subroutine)]
+        );
+        assert_eq!(disassembly.instruction(0x204), Some(Instruction::Call { addr: 0x20A }));
+        let listing = disassembly.listing(Syntax::Cowgod);
+        assert!(listing.contains("CALL sub_20A"));
+        assert!(listing.contains("DB 0xF0"));
+    }
+
+    #[test]
+    fn oversized_rom() {
+        // 0000 is SYS 0x000, so all of it is code up to the end of memory
+        let rom = vec![0; 0x10000];
+        let disassembly = disassemble(&rom);
+        assert!(disassembly.is_code(0xFFFE));
+        let listing = disassembly.listing(Syntax::Cowgod);
+        assert!(listing.lines().last().unwrap().ends_with("; FFFE  0000"));
+    }
+}
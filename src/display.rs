@@ -0,0 +1,386 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// the display is always big enough for the SCHIP high resolution mode, in low resolution only
+// the top left 64x32 pixels are used
+pub const SCREEN_WIDTH: usize = 128;
+pub const SCREEN_HEIGHT: usize = 64;
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+/// number of bitplanes, CHIP-8 and SCHIP only use the first one, XO-CHIP both
+pub const PLANES: usize = 2;
+
+// FNV-1a, stable across Rust versions unlike DefaultHasher, so hashes can be stored in tests
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Display is the monochrome (two planes on XO-CHIP) framebuffer of the CPU.
+///
+/// Each row of a plane is packed into a `u128`, the leftmost pixel in the most significant
+/// bit, so a sprite row is drawn with a shift and an XOR. Rows that change are flagged in a
+/// dirty mask until `take_dirty` is called. Displays compare equal when they show the same
+/// image, whatever their dirty rows.
+#[derive(Clone)]
+pub struct Display {
+    planes: [[u128; SCREEN_HEIGHT]; PLANES],
+    hires: bool,
+    dirty: u64,     //  bit y is set when row y changed
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            planes: [[0; SCREEN_HEIGHT]; PLANES],
+            hires: false,
+            dirty: 0,
+        }
+    }
+
+    /// the active resolution, 64x32 or 128x64
+    pub fn size(&self) -> (usize, usize) {
+        if self.hires {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.size().0
+    }
+
+    pub fn height(&self) -> usize {
+        self.size().1
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// set_hires switches the resolution, which clears all planes
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.planes = [[0; SCREEN_HEIGHT]; PLANES];
+        self.mark_all_dirty();
+    }
+
+    /// clear blanks the planes selected by the bitmask `planes`, bit 0 is plane 1
+    pub fn clear(&mut self, planes: u8) {
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                *rows = [0; SCREEN_HEIGHT];
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// the plane bits of a pixel, 0 when (x, y) is off screen
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let (width, height) = self.size();
+        if x >= width || y >= height {
+            return 0;
+        }
+        let bit = 1u128 << (127 - x);
+        (0..PLANES).fold(0, |pixel, plane| {
+            if self.planes[plane][y] & bit != 0 { pixel | (1 << plane) } else { pixel }
+        })
+    }
+
+    /// set_pixel sets a pixel to the plane bits `planes`, ignoring pixels off screen
+    pub fn set_pixel(&mut self, x: usize, y: usize, planes: u8) {
+        let (width, height) = self.size();
+        if x >= width || y >= height {
+            return;
+        }
+        let bit = 1u128 << (127 - x);
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                rows[y] |= bit;
+            } else {
+                rows[y] &= !bit;
+            }
+        }
+        self.dirty |= 1 << y;
+    }
+
+    /// a row of one plane, pixel x is bit 127 - x
+    pub fn row(&self, plane: usize, y: usize) -> u128 {
+        self.planes[plane][y]
+    }
+
+    /// set_row replaces a row of one plane, bits beyond the active width are dropped
+    pub fn set_row(&mut self, plane: usize, y: usize, bits: u128) {
+        self.planes[plane][y] = bits & self.width_mask();
+        self.dirty |= 1 << y;
+    }
+
+    /// draw_sprite XORs a sprite onto a plane and returns whether any lit pixel was erased.
+    ///
+    /// Each entry of `sprite` is a row of `sprite_width` (8 or 16) bits, right aligned. The
+    /// origin wraps around the screen, the rest of the sprite is clipped at the edges when
+    /// `clip` is set and wraps around otherwise.
+    pub fn draw_sprite(&mut self, plane: usize, x: usize, y: usize, sprite: &[u16], sprite_width: usize, clip: bool) -> bool {
+        let (width, height) = self.size();
+        let (x, y) = (x % width, y % height);
+        let mask = self.width_mask();
+
+        let mut collision = false;
+        for (i, bits) in sprite.iter().enumerate() {
+            let mut row = y + i;
+            if row >= height {
+                if clip {
+                    break;
+                }
+                row %= height;
+            }
+            let left_aligned = (*bits as u128) << (128 - sprite_width);
+            let mut placed = left_aligned >> x;
+            if x + sprite_width > width && !clip {
+                // the pixels past the right edge come back in on the left
+                placed |= left_aligned << (width - x);
+            }
+            placed &= mask;
+
+            let target = &mut self.planes[plane][row];
+            collision |= *target & placed != 0;
+            *target ^= placed;
+            if placed != 0 {
+                self.dirty |= 1 << row;
+            }
+        }
+        collision
+    }
+
+    /// scroll moves the selected planes by (dx, dy) pixels, pixels shifted in are blank
+    pub fn scroll(&mut self, dx: isize, dy: isize, planes: u8) {
+        let height = self.height();
+        let mask = self.width_mask();
+        for (plane, rows) in self.planes.iter_mut().enumerate() {
+            if planes & (1 << plane) == 0 {
+                continue;
+            }
+            let old = *rows;
+            for (y, row) in rows.iter_mut().enumerate().take(height) {
+                let source = y as isize - dy;
+                let bits = if (0..height as isize).contains(&source) { old[source as usize] } else { 0 };
+                // moving right means towards the less significant bits
+                let bits = if dx >= 0 { bits >> dx } else { bits << -dx };
+                *row = bits & mask;
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// the rows changed since the last take_dirty, bit y for row y
+    pub fn dirty_rows(&self) -> u64 {
+        self.dirty
+    }
+
+    /// take_dirty returns the changed rows and clears the dirty mask
+    pub fn take_dirty(&mut self) -> u64 {
+        std::mem::replace(&mut self.dirty, 0)
+    }
+
+    /// mark_all_dirty flags every row of the active resolution, e.g. after a restore
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = u64::MAX >> (64 - self.height());
+    }
+
+    /// diff_rows returns the rows that differ from `other`, all of them if the resolution does
+    pub fn diff_rows(&self, other: &Display) -> u64 {
+        if self.hires != other.hires {
+            return u64::MAX >> (64 - self.height());
+        }
+        (0..self.height())
+            .filter(|&y| (0..PLANES).any(|plane| self.planes[plane][y] != other.planes[plane][y]))
+            .fold(0, |rows, y| rows | (1 << y))
+    }
+
+    /// number of pixels set on any plane
+    pub fn lit_pixels(&self) -> usize {
+        let height = self.height();
+        (0..height)
+            .map(|y| self.planes.iter().fold(0, |row, plane| row | plane[y]).count_ones() as usize)
+            .sum()
+    }
+
+    /// fingerprint is a hash of the resolution and the pixels, suitable for comparing against
+    /// a known good frame
+    pub fn fingerprint(&self) -> u64 {
+        let (width, height) = self.size();
+        let mut hash = FNV_OFFSET;
+        let bytes = [self.hires as u8].into_iter()
+            .chain((0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| self.pixel(x, y)));
+        for byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    // the bits of a row that are on screen in the active resolution
+    fn width_mask(&self) -> u128 {
+        !0u128 << (128 - self.width())
+    }
+}
+
+impl PartialEq for Display {
+    fn eq(&self, other: &Display) -> bool {
+        self.hires == other.hires && self.planes == other.planes
+    }
+}
+
+impl Eq for Display {}
+
+impl Hash for Display {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hires.hash(state);
+        self.planes.hash(state);
+    }
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+// one line per row, '.' for an unlit pixel, '#' for plane 1 and the plane bits otherwise
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (width, height) = self.size();
+        for y in 0..height {
+            for x in 0..width {
+                match self.pixel(x, y) {
+                    0 => write!(f, ".")?,
+                    1 => write!(f, "#")?,
+                    planes => write!(f, "{}", planes)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Display {:?} {:016x}\n{}", self.size(), self.fingerprint(), self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the lit columns of a row
+    fn lit(display: &Display, y: usize) -> Vec<usize> {
+        (0..display.width()).filter(|&x| display.pixel(x, y) != 0).collect()
+    }
+
+    #[test]
+    fn collision() {
+        let mut display = Display::new();
+        assert!(!display.draw_sprite(0, 0, 0, &[0b1100_0000], 8, true));
+        // overlapping an unlit pixel only is no collision
+        assert!(!display.draw_sprite(0, 2, 0, &[0b1000_0000], 8, true));
+        assert!(display.draw_sprite(0, 0, 0, &[0b0100_0000], 8, true));
+        assert_eq!(lit(&display, 0), [0, 2]);
+        // drawing twice erases the sprite
+        assert!(display.draw_sprite(0, 0, 0, &[0b1010_0000], 8, true));
+        assert_eq!(display.lit_pixels(), 0);
+    }
+
+    #[test]
+    fn planes() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, 0, &[0b1000_0000], 8, true);
+        // a sprite on the other plane doesn't collide with the first one
+        assert!(!display.draw_sprite(1, 0, 0, &[0b1100_0000], 8, true));
+        assert_eq!((display.pixel(0, 0), display.pixel(1, 0)), (3, 2));
+        assert_eq!(display.row(1, 0), 0b11 << 126);
+    }
+
+    #[test]
+    fn horizontal_wrap_and_clip() {
+        let mut display = Display::new();
+        assert!(!display.draw_sprite(0, 60, 0, &[0xFF], 8, false));
+        assert_eq!(lit(&display, 0), [0, 1, 2, 3, 60, 61, 62, 63]);
+
+        let mut display = Display::new();
+        display.draw_sprite(0, 60, 0, &[0xFF], 8, true);
+        assert_eq!(lit(&display, 0), [60, 61, 62, 63]);
+        // nothing is left over past the edge of the low resolution screen
+        assert_eq!(display.row(0, 0), 0xF << 64);
+    }
+
+    #[test]
+    fn vertical_wrap_and_clip() {
+        let sprite = [0x80; 4];
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, 30, &sprite, 8, false);
+        let rows: Vec<_> = (0..32).filter(|&y| display.pixel(0, y) != 0).collect();
+        assert_eq!(rows, [0, 1, 30, 31]);
+
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, 30, &sprite, 8, true);
+        let rows: Vec<_> = (0..32).filter(|&y| display.pixel(0, y) != 0).collect();
+        assert_eq!(rows, [30, 31]);
+    }
+
+    #[test]
+    fn origin_wraps() {
+        // the origin wraps even when the rest of the sprite is clipped
+        let mut display = Display::new();
+        display.draw_sprite(0, 64 + 3, 32 + 5, &[0x80], 8, true);
+        assert_eq!(display.pixel(3, 5), 1);
+        assert_eq!(display.lit_pixels(), 1);
+    }
+
+    #[test]
+    fn hires_16x16() {
+        let mut display = Display::new();
+        display.set_hires(true);
+        let sprite = [0x8001; 16];
+        assert!(!display.draw_sprite(0, 120, 56, &sprite, 16, false));
+        assert_eq!(lit(&display, 56), [7, 120]);
+        assert_eq!(lit(&display, 7), [7, 120]);
+        assert_eq!(display.lit_pixels(), 32);
+        assert!(display.draw_sprite(0, 120, 56, &sprite, 16, false));
+        assert_eq!(display.lit_pixels(), 0);
+
+        display.draw_sprite(0, 120, 56, &sprite, 16, true);
+        assert_eq!(lit(&display, 63), [120]);
+        assert_eq!(display.lit_pixels(), 8);
+        // the right edge of the full width screen
+        display.draw_sprite(0, 112, 0, &[0x0001], 16, true);
+        assert_eq!(display.pixel(127, 0), 1);
+    }
+
+    #[test]
+    fn dirty_rows() {
+        let mut display = Display::new();
+        assert_eq!(display.dirty_rows(), 0);
+        display.draw_sprite(0, 0, 3, &[0x80, 0x00, 0x80], 8, true);
+        // a blank sprite row changes nothing
+        assert_eq!(display.dirty_rows(), 0b101 << 3);
+        assert_eq!(display.take_dirty(), 0b101 << 3);
+        assert_eq!(display.dirty_rows(), 0);
+
+        display.draw_sprite(0, 0, 30, &[0x80; 4], 8, false);
+        assert_eq!(display.take_dirty(), 0b11 | 0b11 << 30);
+
+        display.mark_all_dirty();
+        assert_eq!(display.take_dirty(), u32::MAX as u64);
+        display.set_hires(true);
+        assert_eq!(display.take_dirty(), u64::MAX);
+    }
+
+    #[test]
+    fn set_row_drops_bits_off_screen() {
+        let mut display = Display::new();
+        display.set_row(0, 0, !0);
+        assert_eq!(display.row(0, 0), !0 << 64);
+        assert_eq!(display.lit_pixels(), 64);
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+
+/// What went wrong while the CPU was loading or executing a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the opcode does not decode to any instruction
+    UnknownOpcode,
+    /// 2nnn was executed with all 16 stack slots in use
+    StackOverflow,
+    /// 00EE was executed with an empty stack
+    StackUnderflow,
+    /// an instruction or fetch touched memory outside the address space
+    MemoryOutOfBounds { address: usize },
+    /// the program does not fit between PC_START and the end of memory
+    RomTooLarge { size: usize, max: usize },
+}
+
+/// Chip8Error carries the failure kind together with the PC and raw opcode of the
+/// instruction that caused it. `opcode` is 0 when no instruction was being executed,
+/// e.g. when `load_program` rejects a ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chip8Error {
+    pub pc: u16,
+    pub opcode: u16,
+    pub kind: ErrorKind,
+}
+
+impl Chip8Error {
+    pub fn new(pc: u16, opcode: u16, kind: ErrorKind) -> Chip8Error {
+        Chip8Error { pc, opcode, kind }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnknownOpcode => write!(f, "unknown opcode"),
+            ErrorKind::StackOverflow => write!(f, "stack overflow"),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::MemoryOutOfBounds { address } => {
+                write!(f, "memory access out of bounds at 0x{:04X}", address)
+            }
+            ErrorKind::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, at most {} bytes fit in memory", size, max)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (PC:{:04X}, OPCODE:{:04X})", self.kind, self.pc, self.opcode)
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+/// Why a save state was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// the data doesn't start with the save state magic
+    NotAState,
+    /// the state was written by another version of the format
+    UnsupportedVersion(u16),
+    /// the state belongs to a different ROM
+    RomMismatch { expected: u64, found: u64 },
+    /// the data ends before the state does
+    Truncated,
+    /// a field holds a value that can't be restored
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            StateError::RomMismatch { expected, found } => {
+                write!(f, "save state is for ROM {:016x}, the loaded ROM is {:016x}", found, expected)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(field) => write!(f, "save state has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// AsmError is a problem in assembler source, `line` counts from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// OctoError is a problem in Octo source, `line` and `column` count from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctoError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
@@ -2,69 +2,116 @@
 extern crate sdl2;
 
 use sdl2::event::Event;
-use sdl2::pixels::Color;
+use sdl2::EventPump;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::rect::Point;
-use sdl2::render::Canvas;
-use sdl2::version::revision_number;
-use sdl2::video::Window;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
 use sdl2::Sdl;
-use sdl2::keyboard::Keycode;
-use std::time::Duration;
+use sdl2::keyboard::{Keycode, Mod};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use crate::chip8::Protocol;
+use crate::display::{Display, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::protocol::{ControlCommand, FrontendEvent, FrontendLink};
+use crate::runner::Runner;
+use crate::screen::ScreenTrait;
+use crate::timer::{FrameLimiter, TIMER_HZ};
+use crate::sdl_audio::{SdlAudio, ToneSettings};
 
-const WINDOW_WIDTH: u32 = 800;//128;
-const WINDOW_HEIGHT: u32 = 600;//64;
+const WINDOW_WIDTH: u32 = 1024;
+const WINDOW_HEIGHT: u32 = 512;
+// the texture holds the whole hires display, lores frames use its top left corner
+const TEXTURE_PITCH: usize = SCREEN_WIDTH * 3;
+
+// colour of a pixel indexed by its plane bits: background, plane 1, plane 2, both planes
+const PALETTE: [Color; 4] = [
+    Color::RGB(0, 0, 0),
+    Color::RGB(255, 255, 255),
+    Color::RGB(255, 102, 0),
+    Color::RGB(102, 34, 0),
+];
+
+/// How the display is fitted into the window, the image is always centered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// the largest whole number of window pixels per CHIP-8 pixel, sharpest
+    Integer,
+    /// as large as the window allows, keeping the 2:1 aspect ratio
+    Aspect,
+}
 
-#[allow(dead_code)]
 pub struct Screen {
     sdl_context: Sdl,
     canvas: Canvas<Window>,
-    rx: mpsc::Receiver<Protocol>,
+    link: FrontendLink,
+    audio: Option<SdlAudio>,
+    paused: bool,
+    scaling: Scaling,
+    shown: Display,     //  the display as last drawn
+    pixels: Vec<u8>,    //  RGB24 copy of the display, uploaded into the texture
+    size: (usize, usize),   //  the active resolution
+    pending_rows: u64,  //  rows of `pixels` not uploaded yet
+    state_path: Option<PathBuf>,    //  the ROM path, quick save slots are stored next to it
 }
 
+// map the left side of a QWERTY keyboard onto the hex keypad
+//
+//   1 2 3 4        1 2 3 C
+//   Q W E R   ->   4 5 6 D
+//   A S D F        7 8 9 E
+//   Z X C V        A 0 B F
+fn keypad_index(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num1 => Some(0x1),
+        Keycode::Num2 => Some(0x2),
+        Keycode::Num3 => Some(0x3),
+        Keycode::Num4 => Some(0xC),
+        Keycode::Q => Some(0x4),
+        Keycode::W => Some(0x5),
+        Keycode::E => Some(0x6),
+        Keycode::R => Some(0xD),
+        Keycode::A => Some(0x7),
+        Keycode::S => Some(0x8),
+        Keycode::D => Some(0x9),
+        Keycode::F => Some(0xE),
+        Keycode::Z => Some(0xA),
+        Keycode::X => Some(0x0),
+        Keycode::C => Some(0xB),
+        Keycode::V => Some(0xF),
+        _ => None,
+    }
+}
 
-pub trait ScreenTrait {
-    fn clear(&mut self);
-    fn draw_screen(&mut self, screen: [[bool; 32]; 64]);
-    fn draw_point(&mut self, x: i32, y: i32);
-    fn draw(&mut self, text : &str);
-    fn render(&mut self);
+// the quick save slot of F1 to F4
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::F1 => Some(1),
+        Keycode::F2 => Some(2),
+        Keycode::F3 => Some(3),
+        Keycode::F4 => Some(4),
+        _ => None,
+    }
 }
 
+
 impl ScreenTrait for Screen {
     fn clear(&mut self) {
-        // set color to black
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        self.canvas.clear();
+        self.pixels.iter_mut().for_each(|byte| *byte = 0);
+        self.pending_rows = u64::MAX;
     }
 
     fn draw_point(&mut self, x: i32, y: i32) {
-        // set color to white
-        self.canvas.set_draw_color(Color::RGB(255, 255, 255));
-
-        let (w, h) = self.canvas.output_size().unwrap();
-        println!("x: {}, y: {}", x, y);
-
-        let point = Point::new(x, y);
-        
-        // points.fill_with(|| Point::new(rng.gen_range(0..w as i32), rng.gen_range(0..h as i32)));
-        // For performance, it's probably better to draw a whole bunch of points at once
-        self.canvas.draw_point(point).unwrap();
-        // self.canvas.draw_points(points.as_slice()).unwrap();
-
-        self.canvas.present();
+        let (width, height) = self.size;
+        if (0..width as i32).contains(&x) && (0..height as i32).contains(&y) {
+            self.set_pixel(x as usize, y as usize, PALETTE[1]);
+            self.pending_rows |= 1 << y;
+        }
     }
 
-    fn draw_screen(&mut self, screen: [[bool; 32]; 64]) {
-        for x in 0..64 {
-            for y in 0..32 {
-                if screen[x][y] {
-                    self.draw_point(x as i32, y as i32);
-                }
-            }
-        }
+    fn draw_screen(&mut self, display: &Display) {
+        self.size = (0, 0);
+        self.draw_display(display);
     }
 
     fn draw(&mut self, text : &str) {
@@ -88,54 +135,288 @@ impl ScreenTrait for Screen {
         self.canvas.present();
     }
 
+    /// render shows the frames of a runner on another thread until either side quits
     fn render(&mut self) {
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
-
-        'running: loop {
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit { .. }
-                    | Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => {
-                        break 'running
-                    },
-                        _ => {}
-                }
-            }
-
-            let received = self.rx.recv().unwrap();
-            if received.cmd == String::from("CLS") {
-                self.clear();
-            } else if received.cmd == String::from("DRAW") {
-                self.clear();
-                self.draw_screen(received.data);
-            }
-            else {
-            }
-        }
+        self.run_loop(None);
     }
 
 }
 
 impl Screen {
-    pub fn new(name: &str, receiver: mpsc::Receiver<Protocol>) -> Screen {
+    pub fn new(name: &str, link: FrontendLink) -> Screen {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let window = video_subsystem
             .window(name, WINDOW_WIDTH, WINDOW_HEIGHT)
             .position_centered()
+            .resizable()
             .opengl()
             .build()
             .map_err(|e| e.to_string()).unwrap();
 
-        let canvas = window.into_canvas().build().map_err(|e| e.to_string()).unwrap();
+        let canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string()).unwrap();
+
+        // run silently rather than not at all on machines without an audio device
+        let audio = match SdlAudio::new(&sdl_context, ToneSettings::default()) {
+            Ok(audio) => Some(audio),
+            Err(err) => {
+                eprintln!("audio disabled: {}", err);
+                None
+            }
+        };
 
         Screen {
-            sdl_context: sdl_context, 
-            canvas: canvas,
-            rx: receiver,
+            sdl_context,
+            canvas,
+            link,
+            audio,
+            paused: false,
+            scaling: Scaling::Integer,
+            shown: Display::new(),
+            pixels: vec![0; TEXTURE_PITCH * SCREEN_HEIGHT],
+            size: (0, 0),
+            pending_rows: 0,
+            state_path: None,
+        }
+    }
+
+    /// render_inline runs the runner on the calling thread, one frame per loop iteration,
+    /// until the window is closed
+    pub fn render_inline(&mut self, runner: &mut Runner) {
+        self.run_loop(Some(runner));
+    }
+
+    // every iteration is one 60 Hz frame: poll input, run the CPU if it is ours, take the
+    // latest frame and present it
+    fn run_loop(&mut self, mut runner: Option<&mut Runner>) {
+        let mut event_pump = self.sdl_context.event_pump().unwrap();
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+            .map_err(|e| e.to_string()).unwrap();
+        let mut limiter = FrameLimiter::new(TIMER_HZ);
+
+        loop {
+            if !self.poll_input(&mut event_pump) {
+                break;
+            }
+            if let Some(runner) = runner.as_deref_mut() {
+                if !runner.tick() {
+                    break;
+                }
+            }
+            if !self.poll_events() {
+                break;
+            }
+            if self.link.frames.update() {
+                let display = self.link.frames.front().display.clone();
+                self.draw_display(&display);
+            }
+            self.present(&mut texture);
+            // present waits for vsync where available, the limiter keeps 60 Hz on faster
+            // monitors and where it isn't
+            limiter.wait();
+        }
+    }
+
+    // turn window events into commands, false when the window is closed
+    fn poll_input(&mut self, event_pump: &mut EventPump) -> bool {
+        for event in event_pump.poll_iter() {
+            // sends fail once the CPU thread is gone, poll_events notices that
+            let command = match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => {
+                    let _ = self.link.commands.send(ControlCommand::Quit);
+                    return false;
+                },
+                Event::KeyDown { keycode: Some(Keycode::M), repeat: false, .. } => {
+                    if let Some(audio) = &self.audio {
+                        audio.set_muted(!audio.settings().muted);
+                    }
+                    None
+                },
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => {
+                    self.paused = !self.paused;
+                    Some(if self.paused { ControlCommand::Pause } else { ControlCommand::Resume })
+                },
+                Event::KeyDown { keycode: Some(Keycode::N), .. } if self.paused => Some(ControlCommand::Step),
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => Some(ControlCommand::Reset),
+                // Shift+F1..F4 saves into a slot, F1..F4 loads it
+                Event::KeyDown { keycode: Some(keycode), keymod, repeat: false, .. } if state_slot(keycode).is_some() => {
+                    let slot = state_slot(keycode).unwrap();
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        Some(ControlCommand::SaveState(slot))
+                    } else {
+                        self.read_state(slot).map(ControlCommand::LoadState)
+                    }
+                },
+                // the CPU steps back through its history for as long as Backspace is held
+                Event::KeyDown { keycode: Some(Keycode::Backspace), repeat: false, .. } => Some(ControlCommand::Rewind(true)),
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => Some(ControlCommand::Rewind(false)),
+                Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => {
+                    self.scaling = match self.scaling {
+                        Scaling::Integer => Scaling::Aspect,
+                        Scaling::Aspect => Scaling::Integer,
+                    };
+                    None
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    self.toggle_fullscreen();
+                    None
+                },
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    keypad_index(keycode).map(ControlCommand::KeyDown)
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    keypad_index(keycode).map(ControlCommand::KeyUp)
+                },
+                _ => None,
+            };
+            if let Some(command) = command {
+                let _ = self.link.commands.send(command);
+            }
+        }
+        true
+    }
+
+    // handle the events sent since the last frame, false when the CPU thread is gone
+    fn poll_events(&mut self) -> bool {
+        loop {
+            match self.link.events.try_recv() {
+                Ok(event) => self.handle_event(event),
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    /// set_state_path enables the quick save slots, they are stored as `<rom>.<slot>.state`
+    pub fn set_state_path(&mut self, rom: &Path) {
+        self.state_path = Some(rom.to_path_buf());
+    }
+
+    fn slot_path(&self, slot: u8) -> Option<PathBuf> {
+        let rom = self.state_path.as_ref()?;
+        let mut name = rom.as_os_str().to_owned();
+        name.push(format!(".{}.state", slot));
+        Some(PathBuf::from(name))
+    }
+
+    fn read_state(&self, slot: u8) -> Option<Vec<u8>> {
+        let path = self.slot_path(slot)?;
+        match fs::read(&path) {
+            Ok(data) => Some(data),
+            Err(err) => {
+                eprintln!("can't load {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn write_state(&self, slot: u8, data: &[u8]) {
+        let Some(path) = self.slot_path(slot) else {
+            return;
+        };
+        if let Err(err) = fs::write(&path, data) {
+            eprintln!("can't save {}: {}", path.display(), err);
+        }
+    }
+
+    pub fn scaling(&self) -> Scaling {
+        self.scaling
+    }
+
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.scaling = scaling;
+    }
+
+    /// toggle_fullscreen switches between the window and a borderless desktop-sized one
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let mode = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(err) = window.set_fullscreen(mode) {
+            eprintln!("can't switch fullscreen: {}", err);
+        }
+    }
+
+    // convert the rows that changed since the last frame into texture pixels
+    fn draw_display(&mut self, display: &Display) {
+        let size = display.size();
+        // a resolution change makes every row different
+        let rows = if size != self.size { u64::MAX } else { display.diff_rows(&self.shown) };
+        self.size = size;
+        self.shown.clone_from(display);
+        let (width, height) = size;
+        for y in (0..height).filter(|y| rows & (1 << y) != 0) {
+            for x in 0..width {
+                self.set_pixel(x, y, PALETTE[display.pixel(x, y) as usize]);
+            }
+        }
+        self.pending_rows |= rows;
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let offset = y * TEXTURE_PITCH + x * 3;
+        self.pixels[offset..offset + 3].copy_from_slice(&[color.r, color.g, color.b]);
+    }
+
+    // upload the changed rows and present the window
+    fn present(&mut self, texture: &mut Texture) {
+        for y in (0..SCREEN_HEIGHT).filter(|y| self.pending_rows & (1 << y) != 0) {
+            let row = Rect::new(0, y as i32, SCREEN_WIDTH as u32, 1);
+            let start = y * TEXTURE_PITCH;
+            texture.update(row, &self.pixels[start..start + TEXTURE_PITCH], TEXTURE_PITCH).unwrap();
+        }
+        self.pending_rows = 0;
+
+        self.canvas.set_draw_color(PALETTE[0]);
+        self.canvas.clear();
+        let (width, height) = self.size;
+        if width != 0 {
+            let source = Rect::new(0, 0, width as u32, height as u32);
+            self.canvas.copy(texture, source, self.viewport()).unwrap();
+        }
+        self.canvas.present();
+    }
+
+    // where the display goes in the window
+    fn viewport(&self) -> Rect {
+        let (width, height) = (self.size.0 as u32, self.size.1 as u32);
+        let (w, h) = self.canvas.output_size().unwrap();
+        let (dw, dh) = match self.scaling {
+            Scaling::Integer => {
+                let scale = (w / width).min(h / height).max(1);
+                (width * scale, height * scale)
+            }
+            Scaling::Aspect if w * height <= h * width => (w, w * height / width),
+            Scaling::Aspect => (h * width / height, h),
+        };
+        Rect::new((w as i32 - dw as i32) / 2, (h as i32 - dh as i32) / 2, dw.max(1), dh.max(1))
+    }
+
+    fn handle_event(&mut self, event: FrontendEvent) {
+        match event {
+            FrontendEvent::Beep(on) => {
+                if let Some(audio) = &self.audio {
+                    audio.set_tone(on);
+                }
+            }
+            FrontendEvent::Pattern(pattern) => {
+                if let Some(audio) = &self.audio {
+                    audio.set_pattern(pattern);
+                }
+            }
+            // the window stays open on the last frame, F5 restarts the program
+            FrontendEvent::Halted(Some(err)) => eprintln!("CPU halted: {}", err),
+            FrontendEvent::Halted(None) => {}
+            FrontendEvent::StateSaved { slot, data } => self.write_state(slot, &data),
+            FrontendEvent::StateRejected(err) => eprintln!("state not loaded: {}", err),
         }
     }
 
@@ -0,0 +1,514 @@
+// a GDB remote serial protocol stub, see "Remote Protocol" in the GDB manual. A single client
+// is served: packets look like `$m200,10#xx`, where xx is the modulo 256 sum of the data in
+// hex, and are acknowledged with `+` until the client switches to no-ack mode. The client
+// interrupts a running program by sending a lone 0x03 byte.
+
+use crate::debugger::{Debugger, Register, Resume, StopReason, Watch, Watchpoint};
+use crate::protocol::{ChannelSink, RunnerLink};
+use crate::timer::{FrameLimiter, TIMER_HZ};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// the registers are listed in the order of Register::all, so register n of the description
+// is the nth one
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.cpu">
+    <reg name="v0" bitsize="8" type="uint8"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+// what the reader thread found on the connection
+enum Incoming {
+    Packet(String),
+    Corrupt,    //  the checksum didn't match, the client resends after a `-`
+    Interrupt,
+}
+
+// how a packet is answered
+enum Reply {
+    Send(String),
+    Resumed,    //  the stop reply is sent once the program stops
+    End(Option<String>),    //  the client detached or killed the program, with a last reply
+}
+
+/// GdbServer lets a GDB client debug the program of a Debugger over a TCP connection.
+///
+/// The registers are V0-VF, I, PC, SP, DT and ST (see the target description it sends), memory
+/// is the CPU's address space. `Z0`/`Z1` set breakpoints, `Z2`/`Z3`/`Z4` write, read and
+/// access watchpoints, and `s`/`c` step and continue. While the program runs it is paced at
+/// 60 frames per second and shown through the RunnerLink, like with a Runner.
+pub struct GdbServer {
+    debugger: Debugger,
+    no_ack: bool,   //  the client sent QStartNoAckMode
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> GdbServer {
+        GdbServer { debugger, no_ack: false }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    /// serve answers the client on `stream` until it detaches, kills the program or goes
+    /// away, or until the frontend on the other end of `link` quits
+    pub fn serve(&mut self, stream: TcpStream, mut link: RunnerLink) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let (incoming_tx, incoming) = mpsc::channel();
+        thread::spawn(move || read_packets(reader, incoming_tx));
+        let mut out = stream;
+
+        let sink = ChannelSink::new(link.events.clone());
+        self.debugger.cpu_mut().set_audio_sink(Box::new(sink));
+        link.publish(self.debugger.cpu_mut());
+        let mut limiter = FrameLimiter::new(TIMER_HZ);
+        // packets that arrived while the program ran, acknowledged and answered after the stop
+        let mut pending = VecDeque::new();
+
+        loop {
+            if !link.apply_keys(self.debugger.cpu_mut()) {
+                return Ok(());
+            }
+
+            if self.debugger.is_running() {
+                loop {
+                    match incoming.try_recv() {
+                        Ok(Incoming::Interrupt) => self.debugger.interrupt().trigger(),
+                        Ok(Incoming::Packet(packet)) => {
+                            if !self.no_ack {
+                                out.write_all(b"+")?;
+                            }
+                            pending.push_back(packet);
+                        }
+                        Ok(Incoming::Corrupt) => {
+                            if !self.no_ack {
+                                out.write_all(b"-")?;
+                            }
+                        }
+                        Err(mpsc::TryRecvError::Empty) => break,
+                        Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                    }
+                }
+                let stop = self.debugger.run_frame();
+                if self.debugger.cpu().display().dirty_rows() != 0 {
+                    link.publish(self.debugger.cpu_mut());
+                }
+                if let Some(reason) = stop {
+                    send(&mut out, &stop_reply(&reason))?;
+                }
+                limiter.wait();
+                continue;
+            }
+
+            let packet = match pending.pop_front() {
+                Some(packet) => packet,
+                None => match incoming.recv_timeout(Duration::from_millis(16)) {
+                    Ok(Incoming::Packet(packet)) => {
+                        if !self.no_ack {
+                            out.write_all(b"+")?;
+                        }
+                        packet
+                    }
+                    Ok(Incoming::Corrupt) => {
+                        if !self.no_ack {
+                            out.write_all(b"-")?;
+                        }
+                        continue;
+                    }
+                    // a stopped program can't be interrupted
+                    Ok(Incoming::Interrupt) | Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+                },
+            };
+            match self.handle(&packet) {
+                Reply::Send(reply) => send(&mut out, &reply)?,
+                Reply::Resumed => limiter = FrameLimiter::new(TIMER_HZ),
+                Reply::End(reply) => {
+                    if let Some(reply) = reply {
+                        send(&mut out, &reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // answer a packet, an empty reply tells the client that it isn't supported
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            "g" => Register::all().map(|register| self.register_hex(register)).collect(),
+            "G" => self.write_registers(args).unwrap_or_else(error),
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| Register::all().nth(n)) {
+                Some(register) => self.register_hex(register),
+                None => error(()),
+            },
+            "P" => self.write_register(args).unwrap_or_else(error),
+            "m" => self.read_memory(args).unwrap_or_else(error),
+            "M" => self.write_memory(args).unwrap_or_else(error),
+            "Z" | "z" => self.breakpoint(command == "Z", args).unwrap_or_else(error),
+            "s" | "c" => {
+                // the address to resume at is optional
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    self.debugger.set_register(Register::PC, address);
+                }
+                self.debugger.resume(if command == "s" { Resume::Step } else { Resume::Continue });
+                return Reply::Resumed;
+            }
+            "D" => return Reply::End(Some("OK".to_string())),
+            "k" => return Reply::End(None),
+            _ => String::new(),
+        };
+        Reply::Send(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            return read_chunk(TARGET_XML, range).unwrap_or_else(error);
+        }
+        match args {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // little endian like the rest of the protocol
+    fn register_hex(&self, register: Register) -> String {
+        let value = self.debugger.register(register);
+        hex(&value.to_le_bytes()[..register.size()])
+    }
+
+    // G: all registers in the order of g
+    fn write_registers(&mut self, args: &str) -> Result<String, ()> {
+        let mut bytes = unhex(args)?.into_iter();
+        for register in Register::all() {
+            let value: Vec<u8> = bytes.by_ref().take(register.size()).collect();
+            if value.len() != register.size() {
+                return Err(());
+            }
+            self.debugger.set_register(register, le_value(&value));
+        }
+        Ok("OK".to_string())
+    }
+
+    // P n=value
+    fn write_register(&mut self, args: &str) -> Result<String, ()> {
+        let (number, value) = args.split_once('=').ok_or(())?;
+        let number = usize::from_str_radix(number, 16).map_err(|_| ())?;
+        let register = Register::all().nth(number).ok_or(())?;
+        let value = unhex(value)?;
+        if value.len() != register.size() {
+            return Err(());
+        }
+        self.debugger.set_register(register, le_value(&value));
+        Ok("OK".to_string())
+    }
+
+    // m addr,len
+    fn read_memory(&self, args: &str) -> Result<String, ()> {
+        let (address, len) = parse_pair(args)?;
+        let memory = self.debugger.cpu().memory();
+        // a read running past the end of memory returns what there is
+        let start = address.min(memory.len());
+        let end = start.saturating_add(len).min(memory.len());
+        if start == memory.len() && len > 0 {
+            return Err(());
+        }
+        Ok(hex(&memory[start..end]))
+    }
+
+    // M addr,len:data
+    fn write_memory(&mut self, args: &str) -> Result<String, ()> {
+        let (range, data) = args.split_once(':').ok_or(())?;
+        let (address, len) = parse_pair(range)?;
+        let data = unhex(data)?;
+        let memory = self.debugger.cpu_mut().memory_mut();
+        let end = address.checked_add(len).ok_or(())?;
+        if data.len() != len || end > memory.len() {
+            return Err(());
+        }
+        memory[address..end].copy_from_slice(&data);
+        Ok("OK".to_string())
+    }
+
+    // Z/z type,addr,kind: 0 and 1 are breakpoints, 2, 3 and 4 write, read and access
+    // watchpoints with kind as their length
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Result<String, ()> {
+        let mut fields = args.splitn(3, ',');
+        let kind = fields.next().ok_or(())?;
+        let (address, len) = parse_pair(&fields.collect::<Vec<_>>().join(","))?;
+        let address = u16::try_from(address).map_err(|_| ())?;
+        let watch = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Ok("OK".to_string());
+            }
+            "2" => Watch::Write,
+            "3" => Watch::Read,
+            "4" => Watch::Access,
+            _ => return Ok(String::new()),
+        };
+        let watchpoint = Watchpoint { address, len: u16::try_from(len).map_err(|_| ())?.max(1), kind: watch };
+        if insert {
+            self.debugger.add_watchpoint(watchpoint);
+        } else {
+            self.debugger.remove_watchpoint(&watchpoint);
+        }
+        Ok("OK".to_string())
+    }
+}
+
+// split the connection into packets, runs on its own thread until the connection closes
+fn read_packets(stream: TcpStream, incoming: mpsc::Sender<Incoming>) {
+    let mut bytes = BufReader::new(stream).bytes().map_while(Result::ok);
+    while let Some(byte) = bytes.next() {
+        let message = match byte {
+            0x03 => Incoming::Interrupt,
+            b'$' => {
+                let data: Vec<u8> = bytes.by_ref().take_while(|byte| *byte != b'#').collect();
+                let checksum: Vec<u8> = bytes.by_ref().take(2).collect();
+                let expected = std::str::from_utf8(&checksum).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+                let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+                match String::from_utf8(data) {
+                    Ok(packet) if expected == Some(sum) => Incoming::Packet(packet),
+                    _ => Incoming::Corrupt,
+                }
+            }
+            // acks, the replies aren't resent
+            _ => continue,
+        };
+        if incoming.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+// frame a packet
+fn send(out: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(out, "${}#{:02x}", data, checksum)?;
+    out.flush()
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+        StopReason::Watchpoint { address, write, .. } => {
+            let kind = if *write { "watch" } else { "rwatch" };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+        }
+        StopReason::Halted => "W00".to_string(),
+        StopReason::Error(_) => format!("S{:02x}", SIGILL),
+        StopReason::Interrupted => format!("S{:02x}", SIGINT),
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+// the error reply, the number is not looked at by GDB
+fn error(_: ()) -> String {
+    "E01".to_string()
+}
+
+// qXfer replies: `m` and a chunk when there is more, `l` and the rest otherwise
+fn read_chunk(document: &str, range: &str) -> Result<String, ()> {
+    let (offset, len) = parse_pair(range)?;
+    let bytes = document.as_bytes();
+    let start = offset.min(bytes.len());
+    let end = start.saturating_add(len).min(bytes.len());
+    let more = if end < bytes.len() { 'm' } else { 'l' };
+    Ok(format!("{}{}", more, std::str::from_utf8(&bytes[start..end]).map_err(|_| ())?))
+}
+
+// addr,len in hex
+fn parse_pair(text: &str) -> Result<(usize, usize), ()> {
+    let (first, second) = text.split_once(',').ok_or(())?;
+    let first = usize::from_str_radix(first, 16).map_err(|_| ())?;
+    let second = usize::from_str_radix(second, 16).map_err(|_| ())?;
+    Ok((first, second))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, ()> {
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| ())?;
+            if pair.len() != 2 {
+                return Err(());
+            }
+            u8::from_str_radix(pair, 16).map_err(|_| ())
+        })
+        .collect()
+}
+
+fn le_value(bytes: &[u8]) -> u16 {
+    bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+    use crate::quirks::Quirks;
+    use crate::CPU;
+    use std::net::TcpListener;
+
+    // the client end of a connection, acknowledging replies until no-ack mode
+    struct Client {
+        stream: TcpStream,
+        acks: bool,
+    }
+
+    impl Client {
+        fn request(&mut self, packet: &str) -> String {
+            send(&mut self.stream, packet).unwrap();
+            if self.acks {
+                assert_eq!(self.byte(), b'+');
+            }
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+            assert_eq!(checksum, data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+            if self.acks {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    // serve the program on a local port, the server returns the debugger once the client is done
+    fn connect() -> (Client, thread::JoinHandle<Debugger>) {
+        // 200: V0 = 5, 202: V0 += 1, 204: jump 202
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_program(vec![0x60, 0x05, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (runner_link, _frontend_link) = protocol::link();
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = GdbServer::new(Debugger::new(cpu));
+            server.serve(stream, runner_link).unwrap();
+            server.into_debugger()
+        });
+        (Client { stream: TcpStream::connect(address).unwrap(), acks: true }, server)
+    }
+
+    #[test]
+    fn session() {
+        let (mut client, server) = connect();
+        assert!(client.request("qSupported:swbreak+").contains("QStartNoAckMode+"));
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        client.acks = false;
+
+        // V0-VF, I, PC, SP, DT and ST, little endian
+        let registers = client.request("g");
+        assert_eq!(registers.len(), 2 * (16 + 2 + 2 + 3));
+        assert_eq!(&registers[36..40], "0002");
+        assert_eq!(client.request("m200,6"), "600570011202");
+        assert_eq!(client.request("M300,2:abcd"), "OK");
+        assert_eq!(client.request("m300,2"), "abcd");
+        assert_eq!(client.request("Mffffffffffffffff,1:00"), "E01");
+
+        assert_eq!(client.request("Z0,202,2"), "OK");
+        assert_eq!(client.request("c"), "T05swbreak:;");
+        assert_eq!(client.request("p11"), "0202");
+        assert_eq!(client.request("p0"), "05");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p11"), "0402");
+        assert_eq!(client.request("p0"), "06");
+
+        send(&mut client.stream, "k").unwrap();
+        let debugger = server.join().unwrap();
+        assert_eq!(debugger.cpu().memory()[0x300..0x302], [0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn packets_while_running() {
+        let (mut client, server) = connect();
+        send(&mut client.stream, "c").unwrap();
+        assert_eq!(client.byte(), b'+');
+        // acknowledged right away, answered after the stop reply
+        send(&mut client.stream, "p11").unwrap();
+        assert_eq!(client.byte(), b'+');
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.reply().len(), 4);
+
+        send(&mut client.stream, "k").unwrap();
+        assert!(server.join().unwrap().register(Register::V(0)) > 5);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::display::Display;
+use crate::error::{Chip8Error, StateError};
+use crate::protocol::{ControlCommand, FrontendEvent, FrontendLink};
+use crate::screen::ScreenTrait;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// HeadlessScreen is a frontend without a window. It copies the frames of a `Runner` into an
+/// in-memory display, so ROMs can be run on machines without a display server.
+pub struct HeadlessScreen {
+    link: FrontendLink,
+    display: Display,
+    draws: u64,             //  frames taken, frames published in between are skipped
+    beeping: bool,
+    halted: Option<Option<Chip8Error>>,
+    text: Option<String>,   //  the last text passed to draw
+    states: HashMap<u8, Vec<u8>>,   //  saved states by slot
+    rejected: Option<StateError>,   //  why the last LoadState failed
+}
+
+impl HeadlessScreen {
+    pub fn new(link: FrontendLink) -> HeadlessScreen {
+        HeadlessScreen {
+            link,
+            display: Display::new(),
+            draws: 0,
+            beeping: false,
+            halted: None,
+            text: None,
+            states: HashMap::new(),
+            rejected: None,
+        }
+    }
+
+    /// the display as of the last frame received
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn draw_count(&self) -> u64 {
+        self.draws
+    }
+
+    /// whether the buzzer is sounding
+    pub fn is_beeping(&self) -> bool {
+        self.beeping
+    }
+
+    /// Some once the program stopped, holding the error if an instruction failed
+    pub fn halted(&self) -> Option<&Option<Chip8Error>> {
+        self.halted.as_ref()
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// the last state saved into `slot` with ControlCommand::SaveState
+    pub fn state(&self, slot: u8) -> Option<&[u8]> {
+        self.states.get(&slot).map(Vec::as_slice)
+    }
+
+    /// why the last ControlCommand::LoadState was refused, if it was
+    pub fn rejected_state(&self) -> Option<&StateError> {
+        self.rejected.as_ref()
+    }
+
+    /// send passes a command to the runner, e.g. key presses. It fails once the runner is gone.
+    pub fn send(&self, command: ControlCommand) -> Result<(), mpsc::SendError<ControlCommand>> {
+        self.link.commands.send(command)
+    }
+
+    /// update takes the latest frame and the events already sent by the runner without
+    /// waiting for more, it returns whether the display changed. Call it between
+    /// `Runner::run_frame`s on the same thread.
+    pub fn update(&mut self) -> bool {
+        while let Ok(event) = self.link.events.try_recv() {
+            self.apply(event);
+        }
+        self.update_frame()
+    }
+
+    fn update_frame(&mut self) -> bool {
+        if !self.link.frames.update() {
+            return false;
+        }
+        self.draws += 1;
+        let display = self.link.frames.front().display.clone();
+        self.draw_screen(&display);
+        true
+    }
+
+    fn apply(&mut self, event: FrontendEvent) {
+        match event {
+            FrontendEvent::Beep(on) => self.beeping = on,
+            FrontendEvent::Halted(error) => self.halted = Some(error),
+            FrontendEvent::StateSaved { slot, data } => {
+                self.states.insert(slot, data);
+            }
+            FrontendEvent::StateRejected(err) => self.rejected = Some(err),
+            FrontendEvent::Pattern(_) => {}
+        }
+    }
+}
+
+impl ScreenTrait for HeadlessScreen {
+    fn clear(&mut self) {
+        self.display.clear(0b11);
+    }
+
+    fn draw_screen(&mut self, display: &Display) {
+        self.display.clone_from(display);
+    }
+
+    fn draw_point(&mut self, x: i32, y: i32) {
+        if x >= 0 && y >= 0 {
+            self.display.set_pixel(x as usize, y as usize, 1);
+        }
+    }
+
+    fn draw(&mut self, text : &str) {
+        self.text = Some(text.to_string());
+    }
+
+    /// render blocks until the runner goes away, for a runner on another thread
+    fn render(&mut self) {
+        loop {
+            match self.link.events.recv_timeout(Duration::from_millis(16)) {
+                Ok(event) => self.apply(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+            self.update_frame();
+        }
+        self.update_frame();
+    }
+}
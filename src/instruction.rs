@@ -0,0 +1,332 @@
+use std::fmt;
+
+/// A decoded CHIP-8 instruction.
+///
+/// `x` and `y` are register indices (0..=F), `kk` is an immediate byte, `n` a nibble and
+/// `addr` a 12-bit address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Sys { addr: u16 },                  // 0nnn
+    ScrollDown { n: u8 },               // 00Cn (SCHIP)
+    ScrollUp { n: u8 },                 // 00Dn (XO-CHIP)
+    ClearScreen,                        // 00E0
+    Return,                             // 00EE
+    ScrollRight,                        // 00FB (SCHIP)
+    ScrollLeft,                         // 00FC (SCHIP)
+    Exit,                               // 00FD (SCHIP)
+    LowRes,                             // 00FE (SCHIP)
+    HighRes,                            // 00FF (SCHIP)
+    Jump { addr: u16 },                 // 1nnn
+    Call { addr: u16 },                 // 2nnn
+    SkipEqByte { x: u8, kk: u8 },       // 3xkk
+    SkipNeqByte { x: u8, kk: u8 },      // 4xkk
+    SkipEqRegs { x: u8, y: u8 },        // 5xy0
+    SaveRange { x: u8, y: u8 },         // 5xy2 (XO-CHIP)
+    LoadRange { x: u8, y: u8 },         // 5xy3 (XO-CHIP)
+    LoadByte { x: u8, kk: u8 },         // 6xkk
+    AddByte { x: u8, kk: u8 },          // 7xkk
+    LoadRegs { x: u8, y: u8 },          // 8xy0
+    Or { x: u8, y: u8 },                // 8xy1
+    And { x: u8, y: u8 },               // 8xy2
+    Xor { x: u8, y: u8 },               // 8xy3
+    AddRegs { x: u8, y: u8 },           // 8xy4
+    Sub { x: u8, y: u8 },               // 8xy5
+    ShiftRight { x: u8, y: u8 },        // 8xy6
+    SubN { x: u8, y: u8 },              // 8xy7
+    ShiftLeft { x: u8, y: u8 },         // 8xyE
+    SkipNeqRegs { x: u8, y: u8 },       // 9xy0
+    LoadIndex { addr: u16 },            // Annn
+    JumpOffset { addr: u16 },           // Bnnn
+    Random { x: u8, kk: u8 },           // Cxkk
+    LoadLongIndex { addr: u16 },        // F000 nnnn (XO-CHIP), 4 bytes long
+    SelectPlane { n: u8 },              // Fn01 (XO-CHIP)
+    LoadAudio,                          // F002 (XO-CHIP)
+    Draw { x: u8, y: u8, n: u8 },       // Dxyn, Dxy0 draws a 16x16 sprite on SCHIP
+    SkipKeyPressed { x: u8 },           // Ex9E
+    SkipKeyNotPressed { x: u8 },        // ExA1
+    LoadDelayTimer { x: u8 },           // Fx07
+    WaitKey { x: u8 },                  // Fx0A
+    SetDelayTimer { x: u8 },            // Fx15
+    SetSoundTimer { x: u8 },            // Fx18
+    SetPitch { x: u8 },                 // Fx3A (XO-CHIP)
+    AddIndex { x: u8 },                 // Fx1E
+    LoadFont { x: u8 },                 // Fx29
+    LoadBigFont { x: u8 },              // Fx30 (SCHIP)
+    StoreBcd { x: u8 },                 // Fx33
+    StoreRegisters { x: u8 },           // Fx55
+    LoadRegisters { x: u8 },            // Fx65
+    StoreFlags { x: u8 },               // Fx75 (SCHIP)
+    LoadFlags { x: u8 },                // Fx85 (SCHIP)
+    Unknown(u16),
+}
+
+/// decode_long decodes the instruction starting with `opcode`, `next` being the word that
+/// follows it. Only XO-CHIP's F000 nnnn uses the second word.
+pub fn decode_long(opcode: u16, next: u16) -> Instruction {
+    if opcode == 0xF000 {
+        Instruction::LoadLongIndex { addr: next }
+    } else {
+        decode(opcode)
+    }
+}
+
+/// decode turns a raw big-endian opcode into an `Instruction`. Opcodes which are not part of
+/// the instruction set decode to `Instruction::Unknown` instead of failing, so does F000 which
+/// needs the following word, see `decode_long`.
+pub fn decode(opcode: u16) -> Instruction {
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let kk = (opcode & 0x00FF) as u8;
+    let addr = opcode & 0x0FFF;
+
+    match (opcode & 0xF000) >> 12 {
+        0x0 => match addr {
+            0x0C0..=0x0CF => Instruction::ScrollDown { n },
+            0x0D0..=0x0DF => Instruction::ScrollUp { n },
+            0x0E0 => Instruction::ClearScreen,
+            0x0EE => Instruction::Return,
+            0x0FB => Instruction::ScrollRight,
+            0x0FC => Instruction::ScrollLeft,
+            0x0FD => Instruction::Exit,
+            0x0FE => Instruction::LowRes,
+            0x0FF => Instruction::HighRes,
+            _ => Instruction::Sys { addr },
+        },
+        0x1 => Instruction::Jump { addr },
+        0x2 => Instruction::Call { addr },
+        0x3 => Instruction::SkipEqByte { x, kk },
+        0x4 => Instruction::SkipNeqByte { x, kk },
+        0x5 => match n {
+            0x0 => Instruction::SkipEqRegs { x, y },
+            0x2 => Instruction::SaveRange { x, y },
+            0x3 => Instruction::LoadRange { x, y },
+            _ => Instruction::Unknown(opcode),
+        },
+        0x6 => Instruction::LoadByte { x, kk },
+        0x7 => Instruction::AddByte { x, kk },
+        0x8 => match n {
+            0x0 => Instruction::LoadRegs { x, y },
+            0x1 => Instruction::Or { x, y },
+            0x2 => Instruction::And { x, y },
+            0x3 => Instruction::Xor { x, y },
+            0x4 => Instruction::AddRegs { x, y },
+            0x5 => Instruction::Sub { x, y },
+            0x6 => Instruction::ShiftRight { x, y },
+            0x7 => Instruction::SubN { x, y },
+            0xE => Instruction::ShiftLeft { x, y },
+            _ => Instruction::Unknown(opcode),
+        },
+        0x9 if n == 0x0 => Instruction::SkipNeqRegs { x, y },
+        0xA => Instruction::LoadIndex { addr },
+        0xB => Instruction::JumpOffset { addr },
+        0xC => Instruction::Random { x, kk },
+        0xD => Instruction::Draw { x, y, n },
+        0xE => match kk {
+            0x9E => Instruction::SkipKeyPressed { x },
+            0xA1 => Instruction::SkipKeyNotPressed { x },
+            _ => Instruction::Unknown(opcode),
+        },
+        0xF => match kk {
+            0x01 => Instruction::SelectPlane { n: x },
+            0x02 if x == 0 => Instruction::LoadAudio,
+            0x07 => Instruction::LoadDelayTimer { x },
+            0x0A => Instruction::WaitKey { x },
+            0x15 => Instruction::SetDelayTimer { x },
+            0x18 => Instruction::SetSoundTimer { x },
+            0x3A => Instruction::SetPitch { x },
+            0x1E => Instruction::AddIndex { x },
+            0x29 => Instruction::LoadFont { x },
+            0x30 => Instruction::LoadBigFont { x },
+            0x33 => Instruction::StoreBcd { x },
+            0x55 => Instruction::StoreRegisters { x },
+            0x65 => Instruction::LoadRegisters { x },
+            0x75 => Instruction::StoreFlags { x },
+            0x85 => Instruction::LoadFlags { x },
+            _ => Instruction::Unknown(opcode),
+        },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+// helpers to assemble an opcode from its fields
+fn op_xkk(op: u16, x: u8, kk: u8) -> u16 {
+    op << 12 | (x as u16 & 0xF) << 8 | kk as u16
+}
+
+fn op_xyn(op: u16, x: u8, y: u8, n: u8) -> u16 {
+    op << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (n as u16 & 0xF)
+}
+
+fn op_nnn(op: u16, addr: u16) -> u16 {
+    op << 12 | (addr & 0x0FFF)
+}
+
+impl Instruction {
+    /// size in bytes of the instruction in memory
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadLongIndex { .. } => 4,
+            _ => 2,
+        }
+    }
+
+    /// encode is the inverse of `decode`, `decode(i.encode()) == i` holds for every
+    /// instruction produced by the decoder. For `LoadLongIndex` it returns the first word
+    /// only, the address is stored in the word after it.
+    pub fn encode(&self) -> u16 {
+        match *self {
+            Instruction::Sys { addr } => op_nnn(0x0, addr),
+            Instruction::ScrollDown { n } => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp { n } => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ClearScreen => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump { addr } => op_nnn(0x1, addr),
+            Instruction::Call { addr } => op_nnn(0x2, addr),
+            Instruction::SkipEqByte { x, kk } => op_xkk(0x3, x, kk),
+            Instruction::SkipNeqByte { x, kk } => op_xkk(0x4, x, kk),
+            Instruction::SkipEqRegs { x, y } => op_xyn(0x5, x, y, 0x0),
+            Instruction::SaveRange { x, y } => op_xyn(0x5, x, y, 0x2),
+            Instruction::LoadRange { x, y } => op_xyn(0x5, x, y, 0x3),
+            Instruction::LoadByte { x, kk } => op_xkk(0x6, x, kk),
+            Instruction::AddByte { x, kk } => op_xkk(0x7, x, kk),
+            Instruction::LoadRegs { x, y } => op_xyn(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => op_xyn(0x8, x, y, 0x1),
+            Instruction::And { x, y } => op_xyn(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => op_xyn(0x8, x, y, 0x3),
+            Instruction::AddRegs { x, y } => op_xyn(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => op_xyn(0x8, x, y, 0x5),
+            Instruction::ShiftRight { x, y } => op_xyn(0x8, x, y, 0x6),
+            Instruction::SubN { x, y } => op_xyn(0x8, x, y, 0x7),
+            Instruction::ShiftLeft { x, y } => op_xyn(0x8, x, y, 0xE),
+            Instruction::SkipNeqRegs { x, y } => op_xyn(0x9, x, y, 0x0),
+            Instruction::LoadIndex { addr } => op_nnn(0xA, addr),
+            Instruction::JumpOffset { addr } => op_nnn(0xB, addr),
+            Instruction::Random { x, kk } => op_xkk(0xC, x, kk),
+            Instruction::LoadLongIndex { .. } => 0xF000,
+            Instruction::SelectPlane { n } => op_xkk(0xF, n, 0x01),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::Draw { x, y, n } => op_xyn(0xD, x, y, n),
+            Instruction::SkipKeyPressed { x } => op_xkk(0xE, x, 0x9E),
+            Instruction::SkipKeyNotPressed { x } => op_xkk(0xE, x, 0xA1),
+            Instruction::LoadDelayTimer { x } => op_xkk(0xF, x, 0x07),
+            Instruction::WaitKey { x } => op_xkk(0xF, x, 0x0A),
+            Instruction::SetDelayTimer { x } => op_xkk(0xF, x, 0x15),
+            Instruction::SetSoundTimer { x } => op_xkk(0xF, x, 0x18),
+            Instruction::SetPitch { x } => op_xkk(0xF, x, 0x3A),
+            Instruction::AddIndex { x } => op_xkk(0xF, x, 0x1E),
+            Instruction::LoadFont { x } => op_xkk(0xF, x, 0x29),
+            Instruction::LoadBigFont { x } => op_xkk(0xF, x, 0x30),
+            Instruction::StoreBcd { x } => op_xkk(0xF, x, 0x33),
+            Instruction::StoreRegisters { x } => op_xkk(0xF, x, 0x55),
+            Instruction::LoadRegisters { x } => op_xkk(0xF, x, 0x65),
+            Instruction::StoreFlags { x } => op_xkk(0xF, x, 0x75),
+            Instruction::LoadFlags { x } => op_xkk(0xF, x, 0x85),
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+}
+
+// mnemonics follow Cowgod's Chip-8 technical reference, and its SCHIP extension. XO-CHIP
+// has no established mnemonics, its instructions are named after their Octo statements.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Sys { addr } => write!(f, "SYS 0x{:03X}", addr),
+            Instruction::ScrollDown { n } => write!(f, "SCD {}", n),
+            Instruction::ScrollUp { n } => write!(f, "SCU {}", n),
+            Instruction::ClearScreen => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::Jump { addr } => write!(f, "JP 0x{:03X}", addr),
+            Instruction::Call { addr } => write!(f, "CALL 0x{:03X}", addr),
+            Instruction::SkipEqByte { x, kk } => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipNeqByte { x, kk } => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipEqRegs { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LoadByte { x, kk } => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddByte { x, kk } => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::LoadRegs { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddRegs { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeqRegs { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadIndex { addr } => write!(f, "LD I, 0x{:03X}", addr),
+            Instruction::JumpOffset { addr } => write!(f, "JP V0, 0x{:03X}", addr),
+            Instruction::Random { x, kk } => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::LoadLongIndex { addr } => write!(f, "LD I, LONG 0x{:04X}", addr),
+            Instruction::SelectPlane { n } => write!(f, "PLANE {}", n),
+            Instruction::LoadAudio => write!(f, "AUDIO"),
+            Instruction::Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKeyPressed { x } => write!(f, "SKP V{:X}", x),
+            Instruction::SkipKeyNotPressed { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadDelayTimer { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelayTimer { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSoundTimer { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::SetPitch { x } => write!(f, "PITCH V{:X}", x),
+            Instruction::AddIndex { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LoadFont { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LoadBigFont { x } => write!(f, "LD HF, V{:X}", x),
+            Instruction::StoreBcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::StoreRegisters { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadRegisters { x } => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags { x } => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(opcode) => write!(f, "DW 0x{:04X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        // 0nnn covers all of 0xxx that isn't another instruction, hence the large counts
+        for (platform, expected) in [(Platform::Chip8, 48011), (Platform::SuperChip, 48080), (Platform::XoChip, 48641)] {
+            let mut supported = 0;
+            for opcode in 0..=0xFFFF {
+                let instruction = decode(opcode);
+                assert_eq!(instruction.encode(), opcode, "{:04X} on {}", opcode, platform);
+                assert_eq!(decode(instruction.encode()), instruction);
+                if platform.supports(&instruction) {
+                    supported += 1;
+                    assert_eq!(instruction.size(), 2);
+                }
+            }
+            assert_eq!(supported, expected, "{}", platform);
+        }
+    }
+
+    #[test]
+    fn long_index_round_trips() {
+        for address in [0x0000, 0x0200, 0x1234, 0xFFFF] {
+            let instruction = decode_long(0xF000, address);
+            assert_eq!(instruction, Instruction::LoadLongIndex { addr: address });
+            assert_eq!(instruction.size(), 4);
+            assert_eq!(decode_long(instruction.encode(), address), instruction);
+            assert!(Platform::XoChip.supports(&instruction));
+            assert!(!Platform::SuperChip.supports(&instruction));
+        }
+        // without its second word F000 is not an instruction
+        assert_eq!(decode(0xF000), Instruction::Unknown(0xF000));
+    }
+}
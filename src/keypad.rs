@@ -0,0 +1,90 @@
+// the 16-key hex keypad of the COSMAC VIP
+//
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+
+/// A key transition sent from the frontend to the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+/// Keypad keeps the state of the 16 keys as bitmasks, bit n is key n.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Keypad {
+    pressed: u16,
+    // keys which went down since the last clear_latches
+    down_latch: u16,
+    // keys which went down and then up since the last clear_latches
+    released_latch: u16,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad::default()
+    }
+
+    pub fn press(&mut self, key: u8) {
+        let bit = 1 << (key & 0xF);
+        self.pressed |= bit;
+        self.down_latch |= bit;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        let bit = 1 << (key & 0xF);
+        self.pressed &= !bit;
+        if self.down_latch & bit != 0 {
+            self.released_latch |= bit;
+        }
+    }
+
+    pub fn apply(&mut self, event: KeyEvent) {
+        match event {
+            KeyEvent::Down(key) => self.press(key),
+            KeyEvent::Up(key) => self.release(key),
+        }
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.pressed & (1 << (key & 0xF)) != 0
+    }
+
+    /// bitmask of the keys currently held down
+    pub fn state(&self) -> u16 {
+        self.pressed
+    }
+
+    /// clear_latches forgets every transition seen so far, Fx0A calls it when it starts waiting
+    pub fn clear_latches(&mut self) {
+        self.down_latch = 0;
+        self.released_latch = 0;
+    }
+
+    /// to_raw returns the pressed keys and both latches, for save states
+    pub fn to_raw(&self) -> [u16; 3] {
+        [self.pressed, self.down_latch, self.released_latch]
+    }
+
+    /// from_raw is the inverse of to_raw
+    pub fn from_raw(raw: [u16; 3]) -> Keypad {
+        Keypad {
+            pressed: raw[0],
+            down_latch: raw[1],
+            released_latch: raw[2],
+        }
+    }
+
+    /// take_released returns the lowest key which was pressed and released since the last
+    /// clear_latches, and clears the latches
+    pub fn take_released(&mut self) -> Option<u8> {
+        if self.released_latch == 0 {
+            return None;
+        }
+        let key = self.released_latch.trailing_zeros() as u8;
+        self.clear_latches();
+        Some(key)
+    }
+}
@@ -0,0 +1,59 @@
+//! A CHIP-8, SUPER-CHIP and XO-CHIP emulator.
+//!
+//! The emulator core has no dependency on SDL, the `Screen` frontend and the SDL audio
+//! device are only built with the `sdl` feature (on by default).
+
+pub mod asm;
+pub mod audio;
+pub mod chip8;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod font;
+pub mod gdb;
+pub mod headless;
+pub mod instruction;
+pub mod keypad;
+pub mod octo;
+pub mod platform;
+pub mod protocol;
+pub mod quirks;
+pub mod repl;
+pub mod rewind;
+pub mod rng;
+pub mod runner;
+pub mod savestate;
+pub mod screen;
+pub mod timer;
+pub mod triple_buffer;
+
+#[cfg(feature = "sdl")]
+pub mod frontend;
+#[cfg(feature = "sdl")]
+pub mod sdl_audio;
+
+pub use asm::{assemble, assemble_file, Assembly};
+pub use audio::{AudioPattern, AudioSink, NullSink, RecordingSink};
+pub use chip8::{MemoryAccess, Snapshot, CPU, PC_START};
+pub use dap::DapServer;
+pub use debugger::{Debugger, Interrupt, Register, Resume, StopReason, Watch, Watchpoint};
+pub use disasm::{disassemble, Disassembly, LabelKind, Syntax};
+pub use display::{Display, LORES_HEIGHT, LORES_WIDTH, PLANES, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use error::{AsmError, Chip8Error, ErrorKind, OctoError, StateError};
+pub use font::FontStyle;
+pub use gdb::GdbServer;
+pub use headless::HeadlessScreen;
+pub use instruction::{decode, decode_long, Instruction};
+pub use keypad::{KeyEvent, Keypad};
+pub use octo::compile;
+pub use platform::Platform;
+pub use protocol::{link, ControlCommand, Frame, FrontendEvent, FrontendLink, RunnerLink};
+pub use quirks::{IndexIncrement, Quirks};
+pub use repl::Repl;
+pub use rewind::Rewind;
+pub use rng::Rng;
+pub use runner::Runner;
+pub use screen::ScreenTrait;
+pub use timer::Timers;
@@ -1,34 +1,279 @@
+use chip8emu_rs::frontend::Screen;
+use chip8emu_rs::protocol;
+use chip8emu_rs::{assemble_file, compile, disassemble, DapServer, Debugger, GdbServer, Platform, Quirks, Repl, Runner, ScreenTrait, Syntax, CPU};
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-mod frontend;
-mod chip8;
+const USAGE: &str = "\
+usage: chip8emu-rs [--platform chip8|schip|xochip] [--single-thread] <rom or .8o source>
+       chip8emu-rs debug [--platform ...] <rom>
+       chip8emu-rs gdb [--port 1234] [--platform ...] <rom>
+       chip8emu-rs dap [--port N] [--platform ...]
+       chip8emu-rs disasm [--syntax cowgod|octo] <rom>
+       chip8emu-rs asm [-o out.ch8] [--symbols out.sym] <source>";
+const DEFAULT_GDB_PORT: u16 = 1234;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Run,
+    /// run the ROM under the debugger REPL
+    Debug,
+    /// wait for a GDB client on the port
+    Gdb(u16),
+    /// speak the Debug Adapter Protocol on stdio, or wait for a client on the port
+    Dap(Option<u16>),
+    /// print a listing of the ROM
+    Disasm(Syntax),
+    /// assemble the source into a ROM
+    Asm,
+}
 
-use frontend::Screen;
-use frontend::ScreenTrait;
-use chip8::{CPU, Protocol};
-use std::fs::File;
-use std::io::prelude::*;
-use std::sync::mpsc;
+struct Options {
+    mode: Mode,
+    rom: Option<String>,    //  the DAP client names the ROM in its launch request
+    platform: Option<Platform>,     //  None unless given, Octo sources then run as XO-CHIP
+    threaded: bool,     //  run the CPU on its own thread
+    output: Option<String>,     //  where asm writes the ROM, next to the source by default
+    symbols: Option<String>,    //  where asm writes the symbol map
+}
 
+// parse the command line
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let mut mode = match args.next_if(|arg| ["debug", "gdb", "dap", "disasm", "asm"].contains(&arg.as_str())).as_deref() {
+        Some("debug") => Mode::Debug,
+        Some("gdb") => Mode::Gdb(DEFAULT_GDB_PORT),
+        Some("dap") => Mode::Dap(None),
+        Some("disasm") => Mode::Disasm(Syntax::default()),
+        Some("asm") => Mode::Asm,
+        _ => Mode::Run,
+    };
+    let mut rom = None;
+    let mut platform = None;
+    let mut threaded = true;
+    let mut output = None;
+    let mut symbols = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--platform" => {
+                let name = args.next().ok_or("--platform needs a value")?;
+                platform = Some(name.parse()?);
+            }
+            "--single-thread" => threaded = false,
+            "--port" if matches!(mode, Mode::Gdb(_) | Mode::Dap(_)) => {
+                let port = args.next().ok_or("--port needs a value")?;
+                let port = port.parse().map_err(|_| format!("bad port {}", port))?;
+                mode = if let Mode::Dap(_) = mode { Mode::Dap(Some(port)) } else { Mode::Gdb(port) };
+            }
+            "--syntax" if matches!(mode, Mode::Disasm(_)) => {
+                let name = args.next().ok_or("--syntax needs a value")?;
+                mode = Mode::Disasm(name.parse()?);
+            }
+            "-o" | "--output" if mode == Mode::Asm => output = Some(args.next().ok_or("--output needs a value")?),
+            "--symbols" if mode == Mode::Asm => symbols = Some(args.next().ok_or("--symbols needs a value")?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if rom.is_none() && !matches!(mode, Mode::Dap(_)) {
+        return Err("no ROM given".to_string());
+    }
+    Ok(Options { mode, rom, platform, threaded, output, symbols })
+}
 
-fn load_rom_and_spawn(filename: &str, tx:mpsc::Sender<Protocol>) {
-    let mut cpu = CPU::new(tx);
-    let mut file = File::open(filename).unwrap();
-    let mut data = vec![];
-    file.read_to_end(&mut data);
 
-    std::thread::spawn(move || {
-        cpu.load_program(data );
-        cpu.run();
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        process::exit(2);
     });
+    if let (Mode::Asm, Some(source)) = (options.mode, &options.rom) {
+        return asm(source, options.output, options.symbols);
+    }
+    let data = options.rom.as_deref().map(read_rom);
+    if let (Mode::Disasm(syntax), Some(data)) = (options.mode, &data) {
+        print!("{}", disassemble(data).listing(syntax));
+        return;
+    }
+
+    let (runner_link, frontend_link) = protocol::link();
+    let mut screen = Screen::new("Rust Chip8", frontend_link);
+    // Octo programs are written against Octo's defaults, XO-CHIP with its quirks
+    let octo = options.rom.as_deref().is_some_and(is_octo_source);
+    let (platform, quirks) = match options.platform {
+        Some(platform) => (platform, platform.quirks()),
+        None if octo => (Platform::XoChip, Quirks::octo()),
+        None => (Platform::default(), Platform::default().quirks()),
+    };
+    let mut cpu = CPU::new(quirks);
+    cpu.set_platform(platform);
+    let (Some(rom), Some(data)) = (options.rom, data) else {
+        return dap(cpu, options.mode, runner_link, screen);
+    };
+    screen.set_state_path(Path::new(&rom));
+
+    match options.mode {
+        Mode::Run | Mode::Disasm(_) | Mode::Asm => {}
+        Mode::Dap(_) => return dap(cpu, options.mode, runner_link, screen),
+        Mode::Debug => return debug(cpu, data, runner_link, screen),
+        Mode::Gdb(port) => return gdb(cpu, data, port, runner_link, screen),
+    }
+    let mut runner = Runner::new(cpu, runner_link);
+    if let Err(err) = runner.load_rom(data) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    if options.threaded {
+        let cpu_thread = runner.spawn();
+        screen.render();
+        // the screen sent Quit or the CPU thread ended on its own, either way it is done now
+        drop(screen);
+        let _ = cpu_thread.join();
+    } else {
+        screen.render_inline(&mut runner);
+    }
 }
 
+// read the ROM, Octo sources are compiled first
+fn read_rom(path: &str) -> Vec<u8> {
+    let data = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", path, err);
+        process::exit(1);
+    });
+    if !is_octo_source(path) {
+        return data;
+    }
+    match compile(&String::from_utf8_lossy(&data)) {
+        Ok(assembly) => assembly.binary,
+        Err(err) => {
+            eprintln!("{}:{}", path, err);
+            process::exit(1);
+        }
+    }
+}
 
-fn main() {
-    let (tx, rx) = mpsc::channel();
+fn is_octo_source(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|extension| extension == "8o")
+}
 
-    load_rom_and_spawn("./tests/danm8ku.ch8", tx);
+// assemble the source, the ROM goes next to it unless `output` says otherwise
+fn asm(source: &str, output: Option<String>, symbols: Option<String>) {
+    let assembly = assemble_file(Path::new(source)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let output = output.unwrap_or_else(|| Path::new(source).with_extension("ch8").display().to_string());
+    let symbols = symbols.map(|path| (path, assembly.symbol_map().into_bytes()));
+    for (path, contents) in std::iter::once((output, assembly.binary)).chain(symbols) {
+        if let Err(err) = fs::write(&path, contents) {
+            eprintln!("can't write {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
 
-    let mut screen = Screen::new("Rust Chip8", rx);
+// the REPL drives the CPU on its own thread, reading the terminal, while the window shows the
+// display on this one
+fn debug(mut cpu: CPU, data: Vec<u8>, runner_link: protocol::RunnerLink, mut screen: Screen) {
+    if let Err(err) = cpu.load_program(data) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    let (line_tx, line_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let repl_thread = thread::spawn(move || Repl::new(Debugger::new(cpu)).run(runner_link, line_rx));
+    screen.render();
+    drop(screen);
+    let _ = repl_thread.join();
+}
+
+// the window shows the program while a GDB client on the port controls it, the session ends
+// with the connection
+fn gdb(mut cpu: CPU, data: Vec<u8>, port: u16, runner_link: protocol::RunnerLink, mut screen: Screen) {
+    if let Err(err) = cpu.load_program(data) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+        eprintln!("can't listen on port {}: {}", port, err);
+        process::exit(1);
+    });
+    eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+    let server_thread = thread::spawn(move || {
+        // poll, so that closing the window before a client connected ends the wait
+        listener.set_nonblocking(true)?;
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if !runner_link.apply_keys(&mut cpu) {
+                        return Ok(());
+                    }
+                    thread::sleep(Duration::from_millis(16));
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        GdbServer::new(Debugger::new(cpu)).serve(stream, runner_link)
+    });
+    screen.render();
+    drop(screen);
+    if let Ok(Err(err)) = server_thread.join() {
+        eprintln!("gdb connection failed: {}", err);
+    }
+}
+
+// the window shows the program while an editor debugs it over the Debug Adapter Protocol, on
+// stdio or through the port, the client sends the ROM with its launch request
+fn dap(cpu: CPU, mode: Mode, runner_link: protocol::RunnerLink, mut screen: Screen) {
+    let port = if let Mode::Dap(port) = mode { port } else { None };
+    let listener = port.map(|port| {
+        TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|err| {
+            eprintln!("can't listen on port {}: {}", port, err);
+            process::exit(1);
+        })
+    });
+    let server_thread = thread::spawn(move || {
+        let mut server = DapServer::new(Debugger::new(cpu));
+        let Some(listener) = listener else {
+            return server.serve(io::stdin(), io::stdout(), runner_link);
+        };
+        eprintln!("waiting for a DAP client on {}", listener.local_addr()?);
+        // poll, so that closing the window before a client connected ends the wait
+        listener.set_nonblocking(true)?;
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if !runner_link.apply_keys(server.debugger_mut().cpu_mut()) {
+                        return Ok(());
+                    }
+                    thread::sleep(Duration::from_millis(16));
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        server.serve(stream.try_clone()?, stream, runner_link)
+    });
     screen.render();
+    drop(screen);
+    if let Ok(Err(err)) = server_thread.join() {
+        eprintln!("DAP connection failed: {}", err);
+    }
 }
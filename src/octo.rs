@@ -0,0 +1,898 @@
+// a compiler for Octo, the assembly language most CHIP-8 homebrew is written in, see
+// https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md. Tokens are separated by
+// whitespace and `#` starts a comment. Supported are:
+//
+// - every instruction statement, including the XO-CHIP ones (`i := long`, `plane`, `audio`,
+//   `save vx - vy`, `scroll-up`, `pitch := vx`) and bare numbers as data bytes
+// - `: name` labels, `:next`, `:alias`, `:const`, `:calc`, `:byte`, `:call`, `:org`, `:unpack`
+//   and `:macro`; `:breakpoint` and `:monitor` are accepted and ignored
+// - `if ... then`, `if ... begin ... else ... end`, `loop ... while ... again`, with the
+//   comparisons `<`, `>`, `<=` and `>=` computed in vf
+//
+// Programs start at `main`: a `jump main` is put at 0x200 unless the program begins with it.
+
+use crate::asm::Assembly;
+use crate::chip8::PC_START;
+use crate::error::OctoError;
+use std::collections::{HashMap, VecDeque};
+
+const MEMORY_END: usize = 0x10000;
+// a recursive macro would expand forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// compile turns Octo source into a program for PC_START
+pub fn compile(source: &str) -> Result<Assembly, OctoError> {
+    let mut compiler = Compiler { tokens: tokenize(source), ..Compiler::default() };
+    compiler.run()?;
+    Ok(Assembly { binary: compiler.rom, labels: compiler.labels.into_iter().collect() })
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> OctoError {
+        OctoError { line: self.line, column: self.column, message: message.into() }
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// what to fill in once a label forward referenced at the address is defined
+#[derive(Debug, Clone, Copy)]
+enum Patch {
+    /// the low 12 bits of the instruction
+    Address,
+    /// the 16 bit word after `i := long`
+    Long,
+    /// the operand of `v0 := ` from `:unpack`, the nibble goes in front of the high bits
+    UnpackHigh(u8),
+    /// the operand of `v1 := ` from `:unpack`
+    UnpackLow,
+}
+
+enum Block {
+    /// address of the jump over the body, and over the else branch once there is one
+    If { jump: usize, else_jump: Option<usize>, token: Token },
+    /// where `again` jumps to and the jumps of `while`
+    Loop { start: usize, exits: Vec<usize>, token: Token },
+}
+
+#[derive(Default)]
+struct Compiler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    patches: Vec<(Token, usize, Patch)>,
+    blocks: Vec<Block>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn run(&mut self) -> Result<(), OctoError> {
+        self.here = PC_START as usize;
+        let starts_with_main = self.tokens.len() >= 2 && self.tokens[0].text == ":" && self.tokens[1].text == "main";
+        let main_jump = self.tokens.front().cloned().filter(|_| !starts_with_main);
+        if let Some(token) = &main_jump {
+            self.patches.push((Token { text: "main".to_string(), ..token.clone() }, self.here, Patch::Address));
+            self.emit(&[0x10, 0x00], token)?;
+        }
+        while let Some(token) = self.tokens.pop_front() {
+            self.statement(token)?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            return Err(match block {
+                Block::If { token, .. } => token.error("if without end"),
+                Block::Loop { token, .. } => token.error("loop without again"),
+            });
+        }
+        if let (Some(token), false) = (&main_jump, self.labels.contains_key("main")) {
+            return Err(token.error("the program has no main label"));
+        }
+        for (token, address, patch) in std::mem::take(&mut self.patches) {
+            let value = *self.labels.get(&token.text).ok_or_else(|| token.error(format!("undefined name {}", token.text)))?;
+            let offset = address - PC_START as usize;
+            match patch {
+                Patch::Address => {
+                    if value > 0xFFF {
+                        return Err(token.error(format!("{} is above 0xFFF, use i := long", token.text)));
+                    }
+                    self.rom[offset] |= (value >> 8) as u8;
+                    self.rom[offset + 1] = value as u8;
+                }
+                Patch::Long => self.rom[offset..offset + 2].copy_from_slice(&value.to_be_bytes()),
+                Patch::UnpackHigh(nibble) => self.rom[offset] = nibble << 4 | (value >> 8) as u8,
+                Patch::UnpackLow => self.rom[offset] = value as u8,
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, after: &Token) -> Result<Token, OctoError> {
+        self.tokens.pop_front().ok_or_else(|| after.error(format!("unexpected end after {}", after.text)))
+    }
+
+    fn expect(&mut self, after: &Token, text: &str) -> Result<Token, OctoError> {
+        let token = self.next(after)?;
+        if token.text != text {
+            return Err(token.error(format!("expected {} but found {}", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next(&token)?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.next(&token)?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.next(&token)?;
+                let register = self.next(&token)?;
+                let register = self.register(&register)?;
+                self.check_name(&name)?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.next(&token)?;
+                let value = self.next(&token)?;
+                let value = self.value(&value)? as f64;
+                self.define_constant(&name, value)?;
+            }
+            ":calc" => {
+                let name = self.next(&token)?;
+                let open = self.expect(&name, "{")?;
+                let body = self.braced(&open)?;
+                let value = self.calc(&open, &body)?;
+                self.define_constant(&name, value)?;
+            }
+            ":byte" => {
+                let value = self.next(&token)?;
+                let value = if value.text == "{" {
+                    let body = self.braced(&value)?;
+                    self.calc(&value, &body)?.floor() as i64
+                } else {
+                    self.value(&value)?
+                };
+                let byte = byte(&token, value)?;
+                self.emit(&[byte], &token)?;
+            }
+            ":org" => {
+                let address = self.next(&token)?;
+                let value = self.value(&address)?;
+                if !(PC_START as i64..MEMORY_END as i64).contains(&value) {
+                    return Err(address.error(format!("can't put code at 0x{:X}", value)));
+                }
+                self.here = value as usize;
+            }
+            ":call" => {
+                let target = self.next(&token)?;
+                self.jump(0x2000, &target)?;
+            }
+            ":unpack" => {
+                let nibble = self.next(&token)?;
+                let nibble = self.value(&nibble)?;
+                if !(0..=0xF).contains(&nibble) {
+                    return Err(token.error(format!("{} is not a nibble", nibble)));
+                }
+                let target = self.next(&token)?;
+                let value = match self.known_value(&target)? {
+                    Some(value) => value,
+                    None => {
+                        self.patches.push((target.clone(), self.here + 1, Patch::UnpackHigh(nibble as u8)));
+                        self.patches.push((target.clone(), self.here + 3, Patch::UnpackLow));
+                        0
+                    }
+                };
+                if !(0..=0xFFF).contains(&value) {
+                    return Err(target.error(format!("{} is above 0xFFF", target.text)));
+                }
+                self.emit(&[0x60, (nibble << 4 | value >> 8) as u8, 0x61, value as u8], &token)?;
+            }
+            ":macro" => self.define_macro(&token)?,
+            ":breakpoint" => {
+                self.next(&token)?;
+            }
+            ":monitor" => {
+                self.next(&token)?;
+                self.next(&token)?;
+            }
+            "return" | ";" => self.emit(&[0x00, 0xEE], &token)?,
+            "clear" => self.emit(&[0x00, 0xE0], &token)?,
+            "exit" => self.emit(&[0x00, 0xFD], &token)?,
+            "lores" => self.emit(&[0x00, 0xFE], &token)?,
+            "hires" => self.emit(&[0x00, 0xFF], &token)?,
+            "scroll-right" => self.emit(&[0x00, 0xFB], &token)?,
+            "scroll-left" => self.emit(&[0x00, 0xFC], &token)?,
+            "audio" => self.emit(&[0xF0, 0x02], &token)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.next(&token)?;
+                let n = self.nibble(&n)?;
+                let opcode = match token.text.as_str() {
+                    "scroll-down" => 0x00C0 | n as u16,
+                    "scroll-up" => 0x00D0 | n as u16,
+                    _ => 0xF001 | (n as u16) << 8,
+                };
+                self.emit(&opcode.to_be_bytes(), &token)?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.next(&token)?;
+                let x = self.register(&x)?;
+                let low = match token.text.as_str() {
+                    "bcd" => 0x33,
+                    "saveflags" => 0x75,
+                    _ => 0x85,
+                };
+                self.emit(&[0xF0 | x, low], &token)?;
+            }
+            "save" | "load" => {
+                let x = self.next(&token)?;
+                let x = self.register(&x)?;
+                let range = self.tokens.front().is_some_and(|next| next.text == "-");
+                if range {
+                    let dash = self.next(&token)?;
+                    let y = self.next(&dash)?;
+                    let y = self.register(&y)?;
+                    let n = if token.text == "save" { 2 } else { 3 };
+                    self.emit(&[0x50 | x, y << 4 | n], &token)?;
+                } else {
+                    let low = if token.text == "save" { 0x55 } else { 0x65 };
+                    self.emit(&[0xF0 | x, low], &token)?;
+                }
+            }
+            "sprite" => {
+                let x = self.next(&token)?;
+                let x = self.register(&x)?;
+                let y = self.next(&token)?;
+                let y = self.register(&y)?;
+                let n = self.next(&token)?;
+                let n = self.nibble(&n)?;
+                self.emit(&[0xD0 | x, y << 4 | n], &token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.next(&token)?;
+                let opcode = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.jump(opcode, &target)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                let assign = self.expect(&token, ":=")?;
+                let x = self.next(&assign)?;
+                let x = self.register(&x)?;
+                let low = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.emit(&[0xF0 | x, low], &token)?;
+            }
+            "i" => self.index(&token)?,
+            "if" => self.conditional(&token)?,
+            "else" => {
+                let Some(Block::If { jump, else_jump: else_jump @ None, .. }) = self.blocks.last_mut() else {
+                    return Err(token.error("else without if ... begin"));
+                };
+                let jump = *jump;
+                *else_jump = Some(self.here);
+                self.emit(&[0x10, 0x00], &token)?;
+                self.patch_jump(jump, &token)?;
+            }
+            "end" => {
+                let Some(Block::If { jump, else_jump, .. }) = self.blocks.pop() else {
+                    return Err(token.error("end without if ... begin"));
+                };
+                self.patch_jump(else_jump.unwrap_or(jump), &token)?;
+            }
+            "loop" => self.blocks.push(Block::Loop { start: self.here, exits: Vec::new(), token: token.clone() }),
+            "while" => {
+                // skip the exit while the condition holds
+                self.condition(&token, true)?;
+                let exit = self.here;
+                self.emit(&[0x10, 0x00], &token)?;
+                let Some(Block::Loop { exits, .. }) = self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) else {
+                    return Err(token.error("while outside of a loop"));
+                };
+                exits.push(exit);
+            }
+            "again" => {
+                let Some(Block::Loop { start, exits, .. }) = self.blocks.pop() else {
+                    return Err(token.error("again without loop"));
+                };
+                if start > 0xFFF {
+                    return Err(token.error("the loop starts above 0xFFF"));
+                }
+                self.emit(&(0x1000 | start as u16).to_be_bytes(), &token)?;
+                for exit in exits {
+                    self.patch_jump(exit, &token)?;
+                }
+            }
+            _ if self.register(&token).is_ok() => self.assignment(&token)?,
+            _ if self.macros.contains_key(&token.text) => self.expand(&token)?,
+            _ if parse_number(&token.text).is_some() || self.constants.contains_key(&token.text) => {
+                let value = self.value(&token)?;
+                let byte = byte(&token, value)?;
+                self.emit(&[byte], &token)?;
+            }
+            // any other name calls the subroutine at the label
+            _ if is_name(&token.text) => self.jump(0x2000, &token)?,
+            _ => return Err(token.error(format!("unexpected {}", token.text))),
+        }
+        Ok(())
+    }
+
+    // `vx := ...`, `vx += ...` and the other register operations
+    fn assignment(&mut self, target: &Token) -> Result<(), OctoError> {
+        let x = self.register(target)?;
+        let op = self.next(target)?;
+        let source = self.next(&op)?;
+        let y = self.register(&source).ok();
+        let bytes = match (op.text.as_str(), y) {
+            (":=", Some(y)) => [0x80 | x, y << 4],
+            (":=", None) if source.text == "random" => {
+                let mask = self.next(&source)?;
+                let mask = self.value(&mask)?;
+                [0xC0 | x, byte(&source, mask)?]
+            }
+            (":=", None) if source.text == "key" => [0xF0 | x, 0x0A],
+            (":=", None) if source.text == "delay" => [0xF0 | x, 0x07],
+            (":=", None) => [0x60 | x, byte(&source, self.value(&source)?)?],
+            ("+=", Some(y)) => [0x80 | x, y << 4 | 0x4],
+            ("+=", None) => [0x70 | x, byte(&source, self.value(&source)?)?],
+            ("-=", Some(y)) => [0x80 | x, y << 4 | 0x5],
+            ("-=", None) => [0x70 | x, byte(&source, -self.value(&source)?)?],
+            ("=-", Some(y)) => [0x80 | x, y << 4 | 0x7],
+            ("|=", Some(y)) => [0x80 | x, y << 4 | 0x1],
+            ("&=", Some(y)) => [0x80 | x, y << 4 | 0x2],
+            ("^=", Some(y)) => [0x80 | x, y << 4 | 0x3],
+            (">>=", Some(y)) => [0x80 | x, y << 4 | 0x6],
+            ("<<=", Some(y)) => [0x80 | x, y << 4 | 0xE],
+            _ => return Err(op.error(format!("can't use {} with {}", op.text, source.text))),
+        };
+        self.emit(&bytes, target)
+    }
+
+    // `i := ...` and `i += vx`
+    fn index(&mut self, token: &Token) -> Result<(), OctoError> {
+        let op = self.next(token)?;
+        let source = self.next(&op)?;
+        match (op.text.as_str(), source.text.as_str()) {
+            ("+=", _) => {
+                let x = self.register(&source)?;
+                self.emit(&[0xF0 | x, 0x1E], token)
+            }
+            (":=", "hex" | "bighex") => {
+                let x = self.next(&source)?;
+                let x = self.register(&x)?;
+                let low = if source.text == "hex" { 0x29 } else { 0x30 };
+                self.emit(&[0xF0 | x, low], token)
+            }
+            (":=", "long") => {
+                let target = self.next(&source)?;
+                let address = match self.known_value(&target)? {
+                    Some(value) if (0..=0xFFFF).contains(&value) => value as u16,
+                    Some(value) => return Err(target.error(format!("{} is out of range", value))),
+                    None if is_name(&target.text) => {
+                        self.patches.push((target.clone(), self.here + 2, Patch::Long));
+                        0
+                    }
+                    None => return Err(target.error(format!("{} is not an address", target.text))),
+                };
+                let [high, low] = address.to_be_bytes();
+                self.emit(&[0xF0, 0x00, high, low], token)
+            }
+            (":=", _) => self.jump(0xA000, &source),
+            _ => Err(op.error(format!("can't use {} with i", op.text))),
+        }
+    }
+
+    // instructions with an address operand, labels may be defined later
+    fn jump(&mut self, opcode: u16, target: &Token) -> Result<(), OctoError> {
+        let address = match self.known_value(target)? {
+            Some(value) if (0..=0xFFF).contains(&value) => value as u16,
+            Some(value) => return Err(target.error(format!("0x{:X} is above 0xFFF", value))),
+            None if is_name(&target.text) => {
+                self.patches.push((target.clone(), self.here, Patch::Address));
+                0
+            }
+            None => return Err(target.error(format!("{} is not an address", target.text))),
+        };
+        self.emit(&(opcode | address).to_be_bytes(), target)
+    }
+
+    fn conditional(&mut self, token: &Token) -> Result<(), OctoError> {
+        // peek for begin, the condition itself is at most 3 tokens long
+        let block = self.tokens.iter().take(4).find(|next| next.text == "then" || next.text == "begin").map(|next| next.text == "begin");
+        let Some(block) = block else {
+            return Err(token.error("if without then or begin"));
+        };
+        // a block jumps over its body unless the condition holds
+        self.condition(token, block)?;
+        let keyword = self.next(token)?;
+        if keyword.text != "then" && keyword.text != "begin" {
+            return Err(keyword.error(format!("expected then or begin but found {}", keyword.text)));
+        }
+        if block {
+            let jump = self.here;
+            self.emit(&[0x10, 0x00], token)?;
+            self.blocks.push(Block::If { jump, else_jump: None, token: token.clone() });
+        }
+        Ok(())
+    }
+
+    // emit a skip over the next instruction, taken when the condition is `skip_when`
+    fn condition(&mut self, token: &Token, skip_when: bool) -> Result<(), OctoError> {
+        let left = self.next(token)?;
+        let x = self.register(&left)?;
+        let op = self.next(&left)?;
+        let mut comparison = op.text.clone();
+        // skipping when it holds is skipping when the opposite doesn't
+        if skip_when {
+            comparison = match comparison.as_str() {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">=" => "<",
+                ">" => "<=",
+                "<=" => ">",
+                _ => return Err(op.error(format!("unknown comparison {}", op.text))),
+            }
+            .to_string();
+        }
+        match comparison.as_str() {
+            "key" => return self.emit(&[0xE0 | x, 0xA1], &op),
+            "-key" => return self.emit(&[0xE0 | x, 0x9E], &op),
+            _ => {}
+        }
+        let right = self.next(&op)?;
+        let y = self.register(&right).ok();
+        let bytes: Vec<u8> = match (comparison.as_str(), y) {
+            ("==", Some(y)) => vec![0x90 | x, y << 4],
+            ("!=", Some(y)) => vec![0x50 | x, y << 4],
+            ("==", None) => vec![0x40 | x, byte(&right, self.value(&right)?)?],
+            ("!=", None) => vec![0x30 | x, byte(&right, self.value(&right)?)?],
+            // vf := vx - right sets vf to 1 when vx >= right, and right - vx when right >= vx
+            ("<" | ">=" | ">" | "<=", _) => {
+                let ge = matches!(comparison.as_str(), "<" | ">=");
+                let mut bytes = match (ge, y) {
+                    (true, Some(y)) => vec![0x8F, x << 4, 0x8F, y << 4 | 0x5],
+                    (true, None) => vec![0x6F, byte(&right, self.value(&right)?)?, 0x8F, x << 4 | 0x7],
+                    (false, Some(y)) => vec![0x8F, y << 4, 0x8F, x << 4 | 0x5],
+                    (false, None) => vec![0x6F, byte(&right, self.value(&right)?)?, 0x8F, x << 4 | 0x5],
+                };
+                let holds_when = matches!(comparison.as_str(), ">=" | "<=");
+                bytes.extend_from_slice(&[0x3F, if holds_when { 0x00 } else { 0x01 }]);
+                bytes
+            }
+            _ => return Err(op.error(format!("unknown comparison {}", op.text))),
+        };
+        self.emit(&bytes, &op)
+    }
+
+    fn define_macro(&mut self, token: &Token) -> Result<(), OctoError> {
+        let name = self.next(token)?;
+        self.check_name(&name)?;
+        let mut params = Vec::new();
+        let open = loop {
+            let next = self.next(&name)?;
+            if next.text == "{" {
+                break next;
+            }
+            params.push(next.text);
+        };
+        let body = self.braced(&open)?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand(&mut self, token: &Token) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error("too many macro expansions, is a macro recursive?"));
+        }
+        let params = self.macros[&token.text].params.clone();
+        let mut args = HashMap::new();
+        for param in params {
+            let arg = self.next(token)?;
+            args.insert(param, arg.text);
+        }
+        let body = &self.macros[&token.text].body;
+        let expanded: Vec<Token> = body
+            .iter()
+            .map(|body_token| match args.get(&body_token.text) {
+                Some(arg) => Token { text: arg.clone(), ..body_token.clone() },
+                None => body_token.clone(),
+            })
+            .collect();
+        for body_token in expanded.into_iter().rev() {
+            self.tokens.push_front(body_token);
+        }
+        Ok(())
+    }
+
+    // the tokens up to the `}` matching `open`
+    fn braced(&mut self, open: &Token) -> Result<Vec<Token>, OctoError> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.tokens.pop_front().ok_or_else(|| open.error("{ without }"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    // a :calc expression. As in Octo, operators have no precedence and are applied right to
+    // left, `2 * 3 + 1` is 8, parentheses group.
+    fn calc(&self, open: &Token, tokens: &[Token]) -> Result<f64, OctoError> {
+        let (value, rest) = self.calc_expression(open, tokens)?;
+        match rest.first() {
+            Some(token) => Err(token.error(format!("unexpected {}", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn calc_expression<'a>(&self, open: &Token, tokens: &'a [Token]) -> Result<(f64, &'a [Token]), OctoError> {
+        let (left, rest) = self.calc_term(open, tokens)?;
+        let Some(op) = rest.first().filter(|token| token.text != ")") else {
+            return Ok((left, rest));
+        };
+        let (right, rest) = self.calc_expression(op, &rest[1..])?;
+        let value = match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (left as i64 & right as i64) as f64,
+            "|" => (left as i64 | right as i64) as f64,
+            "^" => (left as i64 ^ right as i64) as f64,
+            "<<" => ((left as i64) << (right as i64 & 63)) as f64,
+            ">>" => ((left as i64) >> (right as i64 & 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as i64 as f64,
+            "<=" => (left <= right) as i64 as f64,
+            ">" => (left > right) as i64 as f64,
+            ">=" => (left >= right) as i64 as f64,
+            "==" => (left == right) as i64 as f64,
+            "!=" => (left != right) as i64 as f64,
+            _ => return Err(op.error(format!("unknown operator {}", op.text))),
+        };
+        Ok((value, rest))
+    }
+
+    fn calc_term<'a>(&self, open: &Token, tokens: &'a [Token]) -> Result<(f64, &'a [Token]), OctoError> {
+        let Some((token, rest)) = tokens.split_first() else {
+            return Err(open.error("missing value"));
+        };
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|value| -value),
+            "~" => Some(|value| !(value as i64) as f64),
+            "!" => Some(|value| (value == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            let (value, rest) = self.calc_term(token, rest)?;
+            return Ok((unary(value), rest));
+        }
+        // @ reads the byte compiled at an address
+        if token.text == "@" {
+            let (value, rest) = self.calc_term(token, rest)?;
+            let byte = (value as usize).checked_sub(PC_START as usize).and_then(|offset| self.rom.get(offset)).copied().unwrap_or(0);
+            return Ok((byte as f64, rest));
+        }
+        if token.text == "(" {
+            let (value, rest) = self.calc_expression(token, rest)?;
+            return match rest.split_first() {
+                Some((close, rest)) if close.text == ")" => Ok((value, rest)),
+                _ => Err(token.error("( without )")),
+            };
+        }
+        let value = match (parse_number(&token.text), self.constants.get(&token.text)) {
+            (Some(value), _) => value as f64,
+            (None, Some(value)) => *value,
+            _ if token.text == "HERE" => self.here as f64,
+            _ => *self.labels.get(&token.text).ok_or_else(|| token.error(format!("undefined name {}", token.text)))? as f64,
+        };
+        Ok((value, rest))
+    }
+
+    fn register(&self, token: &Token) -> Result<u8, OctoError> {
+        if let Some(register) = self.aliases.get(&token.text) {
+            return Ok(*register);
+        }
+        let digit = token.text.strip_prefix(['v', 'V']).filter(|digit| digit.len() == 1);
+        digit.and_then(|digit| u8::from_str_radix(digit, 16).ok()).ok_or_else(|| token.error(format!("{} is not a register", token.text)))
+    }
+
+    // a number, constant or label that is already defined
+    fn known_value(&self, token: &Token) -> Result<Option<i64>, OctoError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(Some(value));
+        }
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(Some(value.floor() as i64));
+        }
+        Ok(self.labels.get(&token.text).map(|address| *address as i64))
+    }
+
+    fn value(&self, token: &Token) -> Result<i64, OctoError> {
+        self.known_value(token)?.ok_or_else(|| token.error(format!("undefined name {}", token.text)))
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u8, OctoError> {
+        let value = self.value(token)?;
+        if !(0..=0xF).contains(&value) {
+            return Err(token.error(format!("{} doesn't fit into a nibble", value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), OctoError> {
+        if !is_name(&name.text) || self.register(name).is_ok() {
+            return Err(name.error(format!("{} can't be used as a name", name.text)));
+        }
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) || self.macros.contains_key(&name.text) {
+            return Err(name.error(format!("{} is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: &Token, address: usize) -> Result<(), OctoError> {
+        self.check_name(name)?;
+        self.labels.insert(name.text.clone(), address as u16);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, value: f64) -> Result<(), OctoError> {
+        self.check_name(name)?;
+        self.constants.insert(name.text.clone(), value);
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: &Token) -> Result<(), OctoError> {
+        let end = self.here + bytes.len();
+        if end > MEMORY_END {
+            return Err(token.error("the program doesn't fit into memory"));
+        }
+        let start = self.here - PC_START as usize;
+        if self.rom.len() < end - PC_START as usize {
+            self.rom.resize(end - PC_START as usize, 0);
+        }
+        self.rom[start..start + bytes.len()].copy_from_slice(bytes);
+        self.here = end;
+        Ok(())
+    }
+
+    // point the jump at `at` to here
+    fn patch_jump(&mut self, at: usize, token: &Token) -> Result<(), OctoError> {
+        if self.here > 0xFFF {
+            return Err(token.error("can't jump above 0xFFF"));
+        }
+        let offset = at - PC_START as usize;
+        self.rom[offset..offset + 2].copy_from_slice(&(0x1000 | self.here as u16).to_be_bytes());
+        Ok(())
+    }
+}
+
+fn byte(token: &Token, value: i64) -> Result<u8, OctoError> {
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(token.error(format!("{} doesn't fit into a byte", value)));
+    }
+    Ok(value as u8)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, line) in source.lines().enumerate() {
+        let mut start = None;
+        for (column, c) in line.chars().chain(std::iter::once(' ')).enumerate() {
+            match (start, c.is_whitespace()) {
+                (None, false) if c == '#' => break,
+                (None, false) => start = Some(column),
+                (Some(begin), true) => {
+                    let text: String = line.chars().skip(begin).take(column - begin).collect();
+                    tokens.push_back(Token { text, line: number + 1, column: begin + 1 });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(source: &str) -> Vec<u8> {
+        compile(source).unwrap().binary
+    }
+
+    fn error(source: &str) -> OctoError {
+        compile(source).err().unwrap()
+    }
+
+    #[test]
+    fn main_jump() {
+        assert_eq!(binary(": main clear"), [0x00, 0xE0]);
+        assert_eq!(binary(": sub return : main sub"), [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn if_then() {
+        let source = ": main
+            if v1 == 5 then v2 := 1
+            if v1 != v3 then v2 := 2
+            if v4 key then v2 := 3
+        ";
+        assert_eq!(binary(source), [0x41, 0x05, 0x62, 0x01, 0x51, 0x30, 0x62, 0x02, 0xE4, 0xA1, 0x62, 0x03]);
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = ": main
+            if v0 == 1 begin
+                v1 := 1
+            else
+                v1 := 2
+            end
+        ";
+        assert_eq!(binary(source), [0x30, 0x01, 0x12, 0x08, 0x61, 0x01, 0x12, 0x0A, 0x61, 0x02]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main
+            loop
+                v0 += 1
+                while v0 != 10
+            again
+        ";
+        assert_eq!(binary(source), [0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn comparisons() {
+        // vf := vx - vy leaves vf 1 when vx >= vy, the skip tests it
+        let cases: [(&str, &[u8]); 4] = [
+            ("if v1 < v2 then", &[0x8F, 0x10, 0x8F, 0x25, 0x3F, 0x01]),
+            ("if v1 >= 7 then", &[0x6F, 0x07, 0x8F, 0x17, 0x3F, 0x00]),
+            ("if v1 > v2 then", &[0x8F, 0x20, 0x8F, 0x15, 0x3F, 0x01]),
+            ("if v1 <= 7 then", &[0x6F, 0x07, 0x8F, 0x15, 0x3F, 0x00]),
+        ];
+        for (condition, expected) in cases {
+            let compiled = binary(&format!(": main {} v0 := 1", condition));
+            assert_eq!(compiled[..6], *expected, "{}", condition);
+            assert_eq!(compiled[6..], [0x60, 0x01]);
+        }
+        // in a loop the skip is inverted: `while v1 < v2` leaves when v1 >= v2
+        assert_eq!(
+            binary(": main loop while v1 < v2 again"),
+            [0x8F, 0x10, 0x8F, 0x25, 0x3F, 0x00, 0x12, 0x0A, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let source = "
+            : main
+            :macro bump reg amount { reg += amount reg += amount }
+            bump v3 2
+            bump va 0x10
+        ";
+        assert_eq!(binary(source), [0x73, 0x02, 0x73, 0x02, 0x7A, 0x10, 0x7A, 0x10]);
+        assert_eq!(error(": main :macro forever { forever } forever").message, "too many macro expansions, is a macro recursive?");
+    }
+
+    #[test]
+    fn calc() {
+        // right to left without precedence, like Octo
+        let source = ": main
+            :const WIDTH 8
+            :calc speed { 2 * 3 + 1 }
+            :calc middle { WIDTH / 2 }
+            v0 := speed
+            v1 := middle
+        ";
+        assert_eq!(binary(source), [0x60, 0x08, 0x61, 0x04]);
+    }
+
+    #[test]
+    fn unpack() {
+        let source = ": main
+            :unpack 0xA data
+            : data 1 2
+        ";
+        assert_eq!(binary(source), [0x60, 0xA2, 0x61, 0x04, 0x01, 0x02]);
+        assert_eq!(binary(": main : data 1 2 :unpack 0 data"), [0x01, 0x02, 0x60, 0x02, 0x61, 0x00]);
+    }
+
+    #[test]
+    fn next() {
+        // :next labels the operand of the following instruction, self-modifying code
+        let source = ": main
+            :next counter
+            v0 := 0
+            i := counter
+        ";
+        let assembly = compile(source).unwrap();
+        assert_eq!(assembly.binary, [0x60, 0x00, 0xA2, 0x01]);
+        assert_eq!(assembly.labels["counter"], 0x201);
+    }
+
+    #[test]
+    fn errors() {
+        let err = error(": main\n  if v0 == 1 begin");
+        assert_eq!((err.line, err.column, err.message.as_str()), (2, 3, "if without end"));
+        let err = error(": main\n  jump nowhere");
+        assert_eq!((err.line, err.column, err.message.as_str()), (2, 8, "undefined name nowhere"));
+        let err = error("clear");
+        assert_eq!((err.line, err.column, err.message.as_str()), (1, 1, "the program has no main label"));
+        let err = error(": main\n\tagain");
+        assert_eq!((err.line, err.column, err.message.as_str()), (2, 2, "again without loop"));
+    }
+}
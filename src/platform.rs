@@ -0,0 +1,84 @@
+use crate::instruction::Instruction;
+use crate::quirks::Quirks;
+use std::fmt;
+use std::str::FromStr;
+
+/// The instruction set extension the CPU implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    /// the original CHIP-8, 64x32 display
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1, adds a 128x64 mode, scrolling, 16x16 sprites, a big font and RPL flags
+    SuperChip,
+    /// XO-CHIP, adds 64K of memory, a second bitplane and an audio pattern buffer on top of
+    /// SUPER-CHIP
+    XoChip,
+}
+
+impl Platform {
+    /// the quirks the platform's reference interpreter uses
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::cosmac_vip(),
+            Platform::SuperChip => Quirks::schip_1_1(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    /// size in bytes of the address space
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// supports tells whether the instruction exists on this platform
+    pub fn supports(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::ScrollDown { .. }
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::LoadBigFont { .. }
+            | Instruction::StoreFlags { .. }
+            | Instruction::LoadFlags { .. } => *self != Platform::Chip8,
+            Instruction::ScrollUp { .. }
+            | Instruction::SaveRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LoadLongIndex { .. }
+            | Instruction::SelectPlane { .. }
+            | Instruction::LoadAudio
+            | Instruction::SetPitch { .. } => *self == Platform::XoChip,
+            Instruction::Unknown(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    /// parses the names used on the command line: chip8, schip and xochip
+    fn from_str(name: &str) -> Result<Platform, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("unknown platform {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::Chip8 => write!(f, "chip8"),
+            Platform::SuperChip => write!(f, "schip"),
+            Platform::XoChip => write!(f, "xochip"),
+        }
+    }
+}
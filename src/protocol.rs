@@ -0,0 +1,140 @@
+// the connection between a Runner and a frontend: the runner publishes Frames through a
+// triple buffer and sends FrontendEvents, the frontend sends ControlCommands
+
+use crate::audio::{AudioPattern, AudioSink};
+use crate::chip8::CPU;
+use crate::display::Display;
+use crate::error::{Chip8Error, StateError};
+use crate::triple_buffer::{triple_buffer, Reader, Writer};
+use std::sync::mpsc;
+
+/// Frame is the display at the end of an emulated frame. A new one is only published when
+/// the screen changed, and a frontend that is too slow only ever sees the latest.
+#[derive(Clone, Default)]
+pub struct Frame {
+    pub display: Display,
+    pub number: u64,    //  the CPU's frame count
+}
+
+/// What the CPU thread tells the frontend.
+pub enum FrontendEvent {
+    /// the buzzer started (true) or stopped (false) sounding
+    Beep(bool),
+    /// an XO-CHIP program changed its audio pattern or pitch, None goes back to the plain tone
+    Pattern(Option<AudioPattern>),
+    /// the answer to ControlCommand::SaveState, the state in the save state file format
+    StateSaved { slot: u8, data: Vec<u8> },
+    /// a ControlCommand::LoadState was refused, the machine kept running as it was. Also sent
+    /// when a rewind state failed to load, rewinding then stops and the history is cleared.
+    StateRejected(StateError),
+    /// the program stopped, with the error if an instruction failed and None after 00FD.
+    /// The CPU thread keeps listening, e.g. for Reset or LoadRom.
+    Halted(Option<Chip8Error>),
+}
+
+/// What the frontend tells the CPU thread.
+pub enum ControlCommand {
+    KeyDown(u8),
+    KeyUp(u8),
+    Pause,
+    Resume,
+    /// run a single frame while paused
+    Step,
+    /// restart the current ROM
+    Reset,
+    /// reset and run another ROM
+    LoadRom(Vec<u8>),
+    /// ask for a FrontendEvent::StateSaved, the slot is passed back untouched
+    SaveState(u8),
+    /// restore a save state of the running ROM
+    LoadState(Vec<u8>),
+    /// step back one recorded frame per frame while true, see `Rewind`
+    Rewind(bool),
+    /// stop the CPU thread, dropping the command sender does the same
+    Quit,
+}
+
+/// RunnerLink holds the runner's ends of the connection to a frontend.
+pub struct RunnerLink {
+    pub frames: Writer<Frame>,
+    pub events: mpsc::Sender<FrontendEvent>,
+    pub commands: mpsc::Receiver<ControlCommand>,
+}
+
+/// FrontendLink holds the frontend's ends of the connection to a runner.
+pub struct FrontendLink {
+    pub frames: Reader<Frame>,
+    pub events: mpsc::Receiver<FrontendEvent>,
+    pub commands: mpsc::Sender<ControlCommand>,
+}
+
+impl RunnerLink {
+    /// publish hands the CPU's display to the frontend as the latest frame
+    pub fn publish(&mut self, cpu: &mut CPU) {
+        cpu.take_dirty();
+        let frame = self.frames.back_mut();
+        frame.display.clone_from(cpu.display());
+        frame.number = cpu.frame_count();
+        self.frames.publish();
+    }
+
+    /// apply_keys passes the key presses sent by the frontend to the CPU. Debuggers, which
+    /// decide themselves when the program runs, use it instead of a Runner and ignore the
+    /// other commands. It returns false on Quit and once the frontend is gone.
+    pub fn apply_keys(&self, cpu: &mut CPU) -> bool {
+        loop {
+            match self.commands.try_recv() {
+                Ok(ControlCommand::KeyDown(key)) => cpu.key_down(key),
+                Ok(ControlCommand::KeyUp(key)) => cpu.key_up(key),
+                Ok(ControlCommand::Quit) | Err(mpsc::TryRecvError::Disconnected) => return false,
+                Ok(_) => {}
+                Err(mpsc::TryRecvError::Empty) => return true,
+            }
+        }
+    }
+}
+
+/// link creates a connected pair of ends
+pub fn link() -> (RunnerLink, FrontendLink) {
+    let (frame_writer, frame_reader) = triple_buffer(Frame::default());
+    let (event_tx, event_rx) = mpsc::channel();
+    let (command_tx, command_rx) = mpsc::channel();
+    let runner = RunnerLink {
+        frames: frame_writer,
+        events: event_tx,
+        commands: command_rx,
+    };
+    let frontend = FrontendLink {
+        frames: frame_reader,
+        events: event_rx,
+        commands: command_tx,
+    };
+    (runner, frontend)
+}
+
+/// ChannelSink is the audio sink of a CPU driven by a `Runner`, it turns the buzzer state
+/// into Beep events for the frontend.
+pub struct ChannelSink {
+    tx: mpsc::Sender<FrontendEvent>,
+    on: bool,
+}
+
+impl ChannelSink {
+    pub fn new(tx: mpsc::Sender<FrontendEvent>) -> ChannelSink {
+        ChannelSink { tx, on: false }
+    }
+}
+
+impl AudioSink for ChannelSink {
+    fn set_tone(&mut self, on: bool) {
+        if on != self.on {
+            self.on = on;
+            // a frontend that went away is noticed by the runner through the command channel
+            let _ = self.tx.send(FrontendEvent::Beep(on));
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        let _ = self.tx.send(FrontendEvent::Pattern(pattern));
+    }
+}
@@ -0,0 +1,102 @@
+// behavioural differences between the CHIP-8 interpreters of different eras, see
+// https://chip8.gulrak.net/ for the gory details
+
+/// What Fx55 and Fx65 leave in I after they are done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is left unchanged (SCHIP 1.1)
+    None,
+    /// I is incremented by x (CHIP-48, SCHIP 1.0)
+    X,
+    /// I is incremented by x + 1 (COSMAC VIP, XO-CHIP)
+    XPlusOne,
+}
+
+/// Quirks selects the behaviour of the instructions that differ between platforms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8xy6 and 8xyE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    /// how Fx55 and Fx65 change I
+    pub index_increment: IndexIncrement,
+    /// Bnnn behaves as Bxnn and jumps to xnn + Vx instead of nnn + V0
+    pub jump_uses_vx: bool,
+    /// 8xy1, 8xy2 and 8xy3 reset VF to 0
+    pub vf_reset: bool,
+    /// sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// Dxyn waits for the next frame before the CPU continues, limiting draws to 60 per second
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    /// the original CHIP-8 interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            index_increment: IndexIncrement::XPlusOne,
+            jump_uses_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            index_increment: IndexIncrement::X,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    /// SUPER-CHIP 1.0
+    ///
+    /// SCHIP 1.0 was built on top of CHIP-48 and kept all of its quirks, it only added the
+    /// hires mode and the new instructions, so this is the same as `chip48`.
+    pub fn schip_1_0() -> Quirks {
+        Quirks::chip48()
+    }
+
+    /// SUPER-CHIP 1.1
+    pub fn schip_1_1() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            index_increment: IndexIncrement::None,
+            jump_uses_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    /// the defaults of the Octo IDE
+    ///
+    /// XO-CHIP is specified as whatever Octo does with its default options, so this is the
+    /// same as `xo_chip`. It is kept separate for CHIP-8 programs written in Octo.
+    pub fn octo() -> Quirks {
+        Quirks::xo_chip()
+    }
+
+    /// XO-CHIP as specified by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            index_increment: IndexIncrement::XPlusOne,
+            jump_uses_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
@@ -0,0 +1,427 @@
+use crate::debugger::{Debugger, Register, Resume, StopReason, Watch, Watchpoint};
+use crate::protocol::{ChannelSink, RunnerLink};
+use crate::timer::{FrameLimiter, TIMER_HZ};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+
+const HELP: &str = "\
+break ADDR              stop before the instruction at ADDR (b)
+delete ADDR             remove a breakpoint (d)
+watch [r|w|rw] ADDR [LEN]
+                        stop after an instruction read or wrote memory, w by default
+watch REG               stop after an instruction changed a register
+unwatch ...             remove a watchpoint, same arguments as watch
+info                    list breakpoints and watchpoints
+step                    run one instruction (s)
+next                    run one instruction, a call runs until it returned (n)
+finish                  run until the current subroutine returned
+until ADDR              run until PC is at ADDR
+continue                run until something stops the program, Enter interrupts it (c)
+regs                    show the registers (r)
+mem ADDR [LEN]          dump memory, 64 bytes by default (x)
+disas [ADDR] [COUNT]    disassemble, from PC by default
+set REG VALUE           change a register, e.g. set V3 0x10
+bt                      show the call stack
+quit                    end the session (q)
+An empty line repeats the last command. Numbers are decimal or hex with 0x.";
+
+/// What the driver of a REPL does after a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// print the text and read the next command
+    Print(String),
+    /// resume the program, describe_stop tells what happened once it stops
+    Resume(Resume),
+    Quit,
+}
+
+/// Repl is a gdb-like command line around a Debugger, e.g. `break 0x2A4`, `step`, `regs`.
+/// `execute` only interprets commands, `run` is the interactive loop of `chip8emu-rs debug`.
+pub struct Repl {
+    debugger: Debugger,
+    last_line: String,
+}
+
+impl Repl {
+    pub fn new(debugger: Debugger) -> Repl {
+        Repl {
+            debugger,
+            last_line: String::new(),
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// execute interprets one command line, errors are returned as text to print
+    pub fn execute(&mut self, line: &str) -> Outcome {
+        let line = if line.trim().is_empty() { self.last_line.clone() } else { line.trim().to_string() };
+        self.last_line = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            return Outcome::Print(String::new());
+        };
+        match self.command(command, args) {
+            Ok(outcome) => outcome,
+            Err(err) => Outcome::Print(format!("error: {}", err)),
+        }
+    }
+
+    fn command(&mut self, command: &str, args: &[&str]) -> Result<Outcome, String> {
+        let debugger = &mut self.debugger;
+        let text = match (command, args) {
+            ("break" | "b", [address]) => {
+                let address = parse_address(address)?;
+                debugger.add_breakpoint(address);
+                format!("breakpoint at 0x{:03X}", address)
+            }
+            ("delete" | "d", [address]) => {
+                let address = parse_address(address)?;
+                if !debugger.remove_breakpoint(address) {
+                    return Err(format!("no breakpoint at 0x{:03X}", address));
+                }
+                String::new()
+            }
+            ("watch", [register]) if register.parse::<Register>().is_ok() => {
+                debugger.watch_register(register.parse()?);
+                format!("watching {}", register.to_uppercase())
+            }
+            ("unwatch", [register]) if register.parse::<Register>().is_ok() => {
+                if !debugger.unwatch_register(register.parse()?) {
+                    return Err(format!("{} isn't watched", register.to_uppercase()));
+                }
+                String::new()
+            }
+            ("watch", args) => {
+                let watchpoint = parse_watchpoint(args)?;
+                debugger.add_watchpoint(watchpoint);
+                format!("watching {}", describe_watchpoint(&watchpoint))
+            }
+            ("unwatch", args) => {
+                let watchpoint = parse_watchpoint(args)?;
+                if !debugger.remove_watchpoint(&watchpoint) {
+                    return Err(format!("not watching {}", describe_watchpoint(&watchpoint)));
+                }
+                String::new()
+            }
+            ("info", []) => self.info(),
+            ("step" | "s", []) => return Ok(Outcome::Resume(Resume::Step)),
+            ("next" | "n", []) => return Ok(Outcome::Resume(Resume::StepOver)),
+            ("finish", []) => return Ok(Outcome::Resume(Resume::StepOut)),
+            ("until", [address]) => return Ok(Outcome::Resume(Resume::RunTo(parse_address(address)?))),
+            ("continue" | "c", []) => return Ok(Outcome::Resume(Resume::Continue)),
+            ("regs" | "r", []) => self.registers(),
+            ("mem" | "x", [address]) => self.memory(parse_address(address)?, 64),
+            ("mem" | "x", [address, len]) => self.memory(parse_address(address)?, parse_number(len)? as usize),
+            ("disas", []) => self.disassemble(self.debugger.cpu().pc(), 10),
+            ("disas", [address]) => self.disassemble(parse_address(address)?, 10),
+            ("disas", [address, count]) => self.disassemble(parse_address(address)?, parse_number(count)? as usize),
+            ("set", [register, value]) => {
+                let register: Register = register.parse()?;
+                let value = parse_number(value)?;
+                if value >> (8 * register.size()) != 0 {
+                    return Err(format!("{} doesn't fit into {}", value, register));
+                }
+                debugger.set_register(register, value as u16);
+                String::new()
+            }
+            ("bt", []) => self.backtrace(),
+            ("help" | "h", []) => HELP.to_string(),
+            ("quit" | "q", []) => return Ok(Outcome::Quit),
+            _ => return Err(format!("can't parse `{}`, try help", self.last_line)),
+        };
+        Ok(Outcome::Print(text))
+    }
+
+    /// describe_stop is what is printed when the program stopped: the reason and the next
+    /// instruction
+    pub fn describe_stop(&self, reason: &StopReason) -> String {
+        let next = self.disassemble(self.debugger.cpu().pc(), 1);
+        match reason {
+            StopReason::Stepped | StopReason::Reached(_) => next,
+            _ => format!("stopped: {}\n{}", reason, next),
+        }
+    }
+
+    fn info(&self) -> String {
+        let debugger = &self.debugger;
+        let mut text = String::new();
+        for address in debugger.breakpoints() {
+            let _ = writeln!(text, "breakpoint 0x{:03X}", address);
+        }
+        for watchpoint in debugger.watchpoints() {
+            let _ = writeln!(text, "watchpoint {}", describe_watchpoint(watchpoint));
+        }
+        for register in debugger.watched_registers() {
+            let _ = writeln!(text, "watchpoint {}", register);
+        }
+        if text.is_empty() {
+            text.push_str("no breakpoints or watchpoints");
+        }
+        text.trim_end().to_string()
+    }
+
+    fn registers(&self) -> String {
+        let debugger = &self.debugger;
+        let mut text = String::new();
+        for (x, value) in debugger.cpu().registers().iter().enumerate() {
+            let separator = if x % 8 == 7 { '\n' } else { ' ' };
+            let _ = write!(text, "V{:X}={:02X}{}", x, value, separator);
+        }
+        let _ = write!(
+            text,
+            "I={:04X} PC={:04X} SP={:02X} DT={:02X} ST={:02X}",
+            debugger.register(Register::I),
+            debugger.register(Register::PC),
+            debugger.register(Register::SP),
+            debugger.register(Register::DT),
+            debugger.register(Register::ST),
+        );
+        text
+    }
+
+    // 16 bytes per line, hex and ASCII
+    fn memory(&self, address: u16, len: usize) -> String {
+        let memory = self.debugger.cpu().memory();
+        let start = (address as usize).min(memory.len());
+        let end = (start + len).min(memory.len());
+        let mut lines = Vec::new();
+        for (i, chunk) in memory[start..end].chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' }).collect();
+            lines.push(format!("{:04X}: {:<47}  {}", start + i * 16, hex.join(" "), ascii));
+        }
+        lines.join("\n")
+    }
+
+    // `=>` marks PC and `*` breakpoints
+    fn disassemble(&self, mut address: u16, count: usize) -> String {
+        let debugger = &self.debugger;
+        let breakpoints: Vec<u16> = debugger.breakpoints().collect();
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let Some(instruction) = debugger.instruction_at(address) else {
+                break;
+            };
+            let marker = if address == debugger.cpu().pc() { "=>" } else { "  " };
+            let breakpoint = if breakpoints.contains(&address) { '*' } else { ' ' };
+            lines.push(format!("{}{}{:04X}: {:04X}  {}", marker, breakpoint, address, instruction.encode(), instruction));
+            address = address.wrapping_add(instruction.size());
+        }
+        lines.join("\n")
+    }
+
+    // the return addresses on the stack, innermost first
+    fn backtrace(&self) -> String {
+        let cpu = self.debugger.cpu();
+        let mut lines = vec![format!("#0  0x{:03X}", cpu.pc())];
+        for (depth, address) in cpu.call_stack().iter().enumerate() {
+            lines.push(format!("#{}  0x{:03X}", depth + 1, address));
+        }
+        lines.join("\n")
+    }
+
+    /// run is the interactive loop: it reads commands from `lines`, prints to stdout and
+    /// runs the program at 60 frames per second, publishing frames and taking key presses
+    /// through `link` like a Runner does. A line sent while the program runs interrupts it.
+    /// It returns on quit, when `lines` ends or when the frontend goes away.
+    pub fn run(&mut self, mut link: RunnerLink, lines: mpsc::Receiver<String>) {
+        let sink = ChannelSink::new(link.events.clone());
+        self.debugger.cpu_mut().set_audio_sink(Box::new(sink));
+        let mut limiter = FrameLimiter::new(TIMER_HZ);
+        link.publish(self.debugger.cpu_mut());
+        prompt();
+
+        loop {
+            if !link.apply_keys(self.debugger.cpu_mut()) {
+                return;
+            }
+
+            if self.debugger.is_running() {
+                if lines.try_recv().is_ok() {
+                    self.debugger.interrupt().trigger();
+                }
+                let stop = self.debugger.run_frame();
+                if self.debugger.cpu().display().dirty_rows() != 0 {
+                    link.publish(self.debugger.cpu_mut());
+                }
+                if let Some(reason) = stop {
+                    println!("{}", self.describe_stop(&reason));
+                    prompt();
+                }
+                limiter.wait();
+                continue;
+            }
+
+            let line = match lines.recv_timeout(Duration::from_millis(16)) {
+                Ok(line) => line,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            match self.execute(&line) {
+                Outcome::Print(text) => {
+                    if !text.is_empty() {
+                        println!("{}", text);
+                    }
+                    prompt();
+                }
+                Outcome::Resume(how) => {
+                    self.debugger.resume(how);
+                    limiter = FrameLimiter::new(TIMER_HZ);
+                }
+                Outcome::Quit => return,
+            }
+        }
+    }
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
+}
+
+/// parse_number reads a decimal number or a hex one prefixed with 0x
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("{} isn't a number", text))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let address = parse_number(text)?;
+    u16::try_from(address).map_err(|_| format!("{} is past the end of memory", text))
+}
+
+// [r|w|rw] ADDR [LEN]
+fn parse_watchpoint(args: &[&str]) -> Result<Watchpoint, String> {
+    let (kind, args) = match args.split_first() {
+        Some((&"r", rest)) => (Watch::Read, rest),
+        Some((&"w", rest)) => (Watch::Write, rest),
+        Some((&"rw", rest)) => (Watch::Access, rest),
+        _ => (Watch::Write, args),
+    };
+    let (address, len) = match args {
+        [address] => (parse_address(address)?, 1),
+        [address, len] => (parse_address(address)?, parse_address(len)?),
+        _ => return Err("usage: watch [r|w|rw] ADDR [LEN]".to_string()),
+    };
+    Ok(Watchpoint { address, len: len.max(1), kind })
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let kind = match watchpoint.kind {
+        Watch::Read => "r",
+        Watch::Write => "w",
+        Watch::Access => "rw",
+    };
+    format!("{} 0x{:03X} {}", kind, watchpoint.address, watchpoint.len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+    use crate::CPU;
+
+    fn repl() -> Repl {
+        let mut cpu = CPU::new(Quirks::default());
+        // 200: v0 := 5, 202: jump 200
+        cpu.load_program(vec![0x60, 0x05, 0x12, 0x00]).unwrap();
+        Repl::new(Debugger::new(cpu))
+    }
+
+    fn is_error(outcome: &Outcome) -> bool {
+        matches!(outcome, Outcome::Print(text) if text.starts_with("error: "))
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number("0x2A"), Ok(42));
+        assert_eq!(parse_number("0X2a"), Ok(42));
+        assert_eq!(parse_number("0"), Ok(0));
+        assert!(parse_number("2A").is_err());
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("-1").is_err());
+        assert!(parse_number("0x100000000").is_err());
+        assert_eq!(parse_address("0xFFFF"), Ok(0xFFFF));
+        assert!(parse_address("0x10000").is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut repl = repl();
+        assert_eq!(repl.execute("break 0x202"), Outcome::Print("breakpoint at 0x202".to_string()));
+        assert_eq!(repl.execute("b 514"), Outcome::Print("breakpoint at 0x202".to_string()));
+        assert_eq!(repl.execute("info"), Outcome::Print("breakpoint 0x202".to_string()));
+        assert_eq!(repl.execute("d 0x202"), Outcome::Print(String::new()));
+        assert!(is_error(&repl.execute("delete 0x202")));
+        assert_eq!(repl.debugger().breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn watchpoints() {
+        assert_eq!(parse_watchpoint(&["0x300"]), Ok(Watchpoint { address: 0x300, len: 1, kind: Watch::Write }));
+        assert_eq!(parse_watchpoint(&["r", "0x300", "4"]), Ok(Watchpoint { address: 0x300, len: 4, kind: Watch::Read }));
+        assert_eq!(parse_watchpoint(&["rw", "768", "0"]), Ok(Watchpoint { address: 0x300, len: 1, kind: Watch::Access }));
+        assert!(parse_watchpoint(&[]).is_err());
+        assert!(parse_watchpoint(&["r"]).is_err());
+        assert!(parse_watchpoint(&["x", "0x300"]).is_err());
+        assert!(parse_watchpoint(&["w", "0x300", "4", "5"]).is_err());
+
+        let mut repl = repl();
+        assert_eq!(repl.execute("watch r 0x300 2"), Outcome::Print("watching r 0x300 2".to_string()));
+        assert_eq!(repl.execute("watch v3"), Outcome::Print("watching V3".to_string()));
+        assert_eq!(repl.debugger().watched_registers().collect::<Vec<_>>(), [Register::V(3)]);
+        // the kind and length are part of the watchpoint
+        assert!(is_error(&repl.execute("unwatch w 0x300 2")));
+        assert_eq!(repl.execute("unwatch r 0x300 2"), Outcome::Print(String::new()));
+        assert_eq!(repl.execute("unwatch V3"), Outcome::Print(String::new()));
+        assert!(is_error(&repl.execute("unwatch V3")));
+    }
+
+    #[test]
+    fn resuming() {
+        let mut repl = repl();
+        assert_eq!(repl.execute("step"), Outcome::Resume(Resume::Step));
+        assert_eq!(repl.execute("s"), Outcome::Resume(Resume::Step));
+        assert_eq!(repl.execute("next"), Outcome::Resume(Resume::StepOver));
+        assert_eq!(repl.execute("n"), Outcome::Resume(Resume::StepOver));
+        assert_eq!(repl.execute("finish"), Outcome::Resume(Resume::StepOut));
+        assert_eq!(repl.execute("until 0x202"), Outcome::Resume(Resume::RunTo(0x202)));
+        assert_eq!(repl.execute("c"), Outcome::Resume(Resume::Continue));
+        // an empty line repeats the last command
+        assert_eq!(repl.execute("  "), Outcome::Resume(Resume::Continue));
+        assert_eq!(repl.execute("q"), Outcome::Quit);
+    }
+
+    #[test]
+    fn set_registers() {
+        let mut repl = repl();
+        assert_eq!(repl.execute("set V3 0x10"), Outcome::Print(String::new()));
+        assert_eq!(repl.execute("set i 0xFFF"), Outcome::Print(String::new()));
+        assert_eq!((repl.debugger().register(Register::V(3)), repl.debugger().register(Register::I)), (0x10, 0xFFF));
+        assert!(is_error(&repl.execute("set V3 256")));
+        assert!(is_error(&repl.execute("set V16 1")));
+        assert!(is_error(&repl.execute("set V3")));
+        assert_eq!(repl.debugger().register(Register::V(3)), 0x10);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut repl = repl();
+        for line in ["frobnicate", "break", "break 0x202 0x204", "break 0xZZ", "b 70000", "step 2", "until", "mem", "disas 1 2 3"] {
+            assert!(is_error(&repl.execute(line)), "{}", line);
+        }
+        assert_eq!(repl.execute("bogus"), Outcome::Print("error: can't parse `bogus`, try help".to_string()));
+    }
+}
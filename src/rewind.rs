@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+
+// the history is kept as the newest save state in full plus, for every older state, the delta
+// that turns the state after it back into it. A delta is the XOR of the two states with the
+// runs of zero bytes (everything that didn't change) squeezed out, so a frame that only
+// touched a few registers and a sprite costs a few dozen bytes instead of a whole memory copy.
+// Stepping back applies the newest delta to the full state, the oldest deltas are dropped
+// when the budget is exceeded.
+
+/// how much memory the history may use unless set otherwise
+pub const DEFAULT_BUDGET: usize = 8 * 1024 * 1024;
+
+/// Rewind is a bounded history of save states, see `CPU::save_state`.
+pub struct Rewind {
+    budget: usize,          //  bytes
+    interval: u32,          //  frames between recorded states
+    countdown: u32,         //  frames until the next state is recorded
+    current: Vec<u8>,       //  the newest state, empty before the first push
+    deltas: VecDeque<Delta>,    //  oldest first
+    size: usize,            //  bytes used by current and deltas
+}
+
+struct Delta {
+    len: usize,     //  length of the older state
+    runs: Vec<u8>,  //  see encode_delta
+}
+
+impl Rewind {
+    /// new keeps a state every `interval` frames in at most `budget` bytes
+    pub fn new(budget: usize, interval: u32) -> Rewind {
+        Rewind {
+            budget,
+            interval: interval.max(1),
+            countdown: 0,
+            current: Vec::new(),
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// the memory used by the history in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// the number of states that can be stepped back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// due is called once per emulated frame and tells whether a state should be pushed
+    pub fn due(&mut self) -> bool {
+        if self.countdown == 0 {
+            self.countdown = self.interval - 1;
+            return true;
+        }
+        self.countdown -= 1;
+        false
+    }
+
+    /// push records the newest state
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.current.is_empty() {
+            let delta = Delta {
+                len: self.current.len(),
+                runs: encode_delta(&state, &self.current),
+            };
+            self.size += delta.runs.len();
+            self.deltas.push_back(delta);
+        }
+        self.size = self.size - self.current.len() + state.len();
+        self.current = state;
+
+        while self.size > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.runs.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// step_back goes back one recorded state and returns it, None when there is none left.
+    /// Pushing afterwards continues the history from the returned state.
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        self.size -= delta.runs.len();
+        self.size = self.size - self.current.len() + delta.len;
+        apply_delta(&mut self.current, &delta);
+        self.countdown = 0;
+        Some(&self.current)
+    }
+
+    /// clear forgets the history, e.g. when another ROM is loaded
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.deltas.clear();
+        self.size = 0;
+        self.countdown = 0;
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind::new(DEFAULT_BUDGET, 1)
+    }
+}
+
+// a delta is a sequence of (zero run, literal length, literal bytes) where the lengths are
+// LEB128 varints and the literals are the XOR of both states. Bytes past the end of the
+// shorter state count as zero.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let len = from.len().max(to.len());
+    let byte = |i: usize| from.get(i).copied().unwrap_or(0) ^ to.get(i).copied().unwrap_or(0);
+
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && byte(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        // a single unchanged byte in between is cheaper to keep in the literal
+        while i < len && (byte(i) != 0 || (i + 1 < len && byte(i + 1) != 0)) {
+            i += 1;
+        }
+        if literal_start == len {
+            break;
+        }
+        push_varint(&mut runs, literal_start - zeros_start);
+        push_varint(&mut runs, i - literal_start);
+        runs.extend((literal_start..i).map(byte));
+    }
+    runs
+}
+
+fn apply_delta(state: &mut Vec<u8>, delta: &Delta) {
+    state.resize(state.len().max(delta.len), 0);
+    let mut runs = delta.runs.as_slice();
+    let mut i = 0;
+    while !runs.is_empty() {
+        i += read_varint(&mut runs);
+        let count = read_varint(&mut runs);
+        for (target, byte) in state[i..i + count].iter_mut().zip(&runs[..count]) {
+            *target ^= byte;
+        }
+        runs = &runs[count..];
+        i += count;
+    }
+    state.truncate(delta.len);
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[0];
+        *input = &input[1..];
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioPattern, RecordingSink};
+    use crate::platform::Platform;
+    use crate::CPU;
+
+    // the delta from `newer` back to `older` has to restore `older` exactly
+    fn round_trip(older: &[u8], newer: &[u8]) -> usize {
+        let delta = Delta { len: older.len(), runs: encode_delta(newer, older) };
+        let mut state = newer.to_vec();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, older);
+        delta.runs.len()
+    }
+
+    // the bytes of the states that differ from the base, all past 200 bytes
+    fn state(seed: u8) -> Vec<u8> {
+        let mut state = vec![0; 1000];
+        state[200..220].iter_mut().for_each(|byte| *byte = seed);
+        state
+    }
+
+    #[test]
+    fn identical_states() {
+        assert_eq!(round_trip(&[1, 2, 3], &[1, 2, 3]), 0);
+        assert_eq!(round_trip(&[], &[]), 0);
+    }
+
+    #[test]
+    fn different_states() {
+        let older: Vec<u8> = (0..=255).collect();
+        let newer: Vec<u8> = older.iter().map(|byte| !byte).collect();
+        // one run without zeros: a zero length, a two byte literal length and the literal
+        assert_eq!(round_trip(&older, &newer), 1 + 2 + 256);
+        // a single unchanged byte is kept in the literal
+        assert_eq!(round_trip(&[1, 2, 3], &[9, 2, 9]), 2 + 3);
+    }
+
+    #[test]
+    fn long_runs() {
+        for gap in [127, 128, 16383, 16384, 100_000] {
+            let mut older = vec![0; gap + 2];
+            older[gap] = 0xAA;
+            let newer = vec![0; gap + 2];
+            round_trip(&older, &newer);
+            round_trip(&newer, &older);
+        }
+        let older = vec![0x55; 20_000];
+        round_trip(&older, &vec![0; 20_000]);
+    }
+
+    #[test]
+    fn different_lengths() {
+        round_trip(&[1, 2, 3, 4, 5], &[1, 2]);
+        round_trip(&[1, 2], &[1, 2, 3, 4, 5]);
+        round_trip(&[], &[7; 300]);
+        round_trip(&[0; 300], &[]);
+    }
+
+    #[test]
+    fn varints() {
+        for (value, len) in [(0, 1), (0x7F, 1), (0x80, 2), (0x3FFF, 2), (0x4000, 3), (u32::MAX as usize, 5), (usize::MAX, 10)] {
+            let mut bytes = Vec::new();
+            push_varint(&mut bytes, value);
+            assert_eq!(bytes.len(), len, "{:#X}", value);
+            let mut input = bytes.as_slice();
+            assert_eq!(read_varint(&mut input), value);
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn step_back_through_history() {
+        let mut rewind = Rewind::default();
+        assert_eq!(rewind.step_back(), None);
+        rewind.push(state(1));
+        // the newest state is where the program is, there is nothing before it
+        assert_eq!(rewind.step_back(), None);
+        assert!(rewind.is_empty());
+        rewind.push(state(2));
+        rewind.push(state(3));
+        assert_eq!(rewind.len(), 2);
+        assert_eq!(rewind.step_back(), Some(state(2).as_slice()));
+        assert_eq!(rewind.step_back(), Some(state(1).as_slice()));
+        assert_eq!(rewind.step_back(), None);
+        assert_eq!(rewind.size(), 1000);
+        rewind.clear();
+        assert_eq!((rewind.size(), rewind.step_back()), (0, None));
+    }
+
+    #[test]
+    fn eviction() {
+        // the full state plus three deltas, a 200 byte zero run takes a two byte varint
+        let mut rewind = Rewind::new(1000 + 3 * (2 + 1 + 20), 1);
+        for seed in 1..=10 {
+            rewind.push(state(seed));
+            assert!(rewind.size() <= rewind.budget());
+        }
+        assert_eq!(rewind.len(), 3);
+        for seed in (7..=9).rev() {
+            assert_eq!(rewind.step_back(), Some(state(seed).as_slice()));
+        }
+        assert_eq!(rewind.step_back(), None);
+
+        // a budget below one state keeps just the newest one
+        let mut rewind = Rewind::new(10, 1);
+        rewind.push(state(1));
+        rewind.push(state(2));
+        assert_eq!((rewind.len(), rewind.size()), (0, 1000));
+    }
+
+    #[test]
+    fn interval() {
+        let mut rewind = Rewind::new(DEFAULT_BUDGET, 3);
+        let due: Vec<bool> = (0..7).map(|_| rewind.due()).collect();
+        assert_eq!(due, [true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn rewinding_restores_the_audio_pattern() {
+        // 200: i := 206, 202: audio, 204: jump 204, 206: the pattern
+        let mut program = vec![0xA2, 0x06, 0xF0, 0x02, 0x12, 0x04];
+        program.extend_from_slice(&[0xCC; 16]);
+        let mut cpu = CPU::new(Platform::XoChip.quirks());
+        cpu.set_platform(Platform::XoChip);
+        let sink = RecordingSink::new();
+        cpu.set_audio_sink(Box::new(sink.clone()));
+        cpu.load_program(program).unwrap();
+
+        let mut rewind = Rewind::default();
+        rewind.push(cpu.save_state());
+        cpu.run_frame().unwrap();
+        rewind.push(cpu.save_state());
+        assert_eq!(sink.pattern(), Some(AudioPattern { buffer: [0xCC; 16], ..AudioPattern::default() }));
+
+        // back before the program loaded its pattern the buzzer plays the plain tone
+        let state = rewind.step_back().unwrap().to_vec();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(sink.pattern(), None);
+    }
+}
@@ -0,0 +1,47 @@
+// the random number generator of Cxkk
+//
+// xorshift64* keeps its whole state in a single u64, so unlike rand's generators it can be
+// saved with the machine and a restored state produces the same numbers again
+
+const MULTIPLIER: u64 = 0x2545F4914F6CDD1D;
+// xorshift never leaves the all-zero state, so it is replaced by this one
+const ZERO_SEED: u64 = 0x9E3779B97F4A7C15;
+
+/// Rng is a small, fast and reproducible pseudo random number generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// new creates a generator from a seed, the same seed always gives the same numbers
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: if seed == 0 { ZERO_SEED } else { seed },
+        }
+    }
+
+    /// from_entropy creates a generator with a random seed
+    pub fn from_entropy() -> Rng {
+        Rng::new(rand::random())
+    }
+
+    /// the current state, `Rng::new(rng.state())` continues where `rng` is
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(MULTIPLIER)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        // the high bits are the best ones of xorshift64*
+        (self.next_u64() >> 56) as u8
+    }
+}
@@ -0,0 +1,207 @@
+use crate::chip8::CPU;
+use crate::error::Chip8Error;
+use crate::protocol::{ChannelSink, ControlCommand, FrontendEvent, RunnerLink};
+use crate::rewind::Rewind;
+use crate::timer::{FrameLimiter, TIMER_HZ};
+use std::sync::mpsc;
+use std::thread;
+
+/// Runner drives a CPU one 60 Hz frame at a time. It applies the ControlCommands of a
+/// frontend, publishes a Frame whenever the screen changed and reports everything else with
+/// FrontendEvents.
+///
+/// Every frame it runs is recorded into a `Rewind` history, which the frontend plays
+/// backwards while it holds ControlCommand::Rewind.
+///
+/// It either runs on its own thread (`spawn`), or the frontend calls `tick` once per frame
+/// on its own thread.
+pub struct Runner {
+    cpu: CPU,
+    rom: Vec<u8>,   //  reloaded by Reset
+    link: RunnerLink,
+    paused: bool,
+    stopped: bool,  //  the program halted or failed, only commands are processed
+    rewind: Rewind,
+    rewinding: bool,    //  frames step back through the history instead of running
+}
+
+impl Runner {
+    /// new takes over the CPU, its audio sink is replaced by one sending Beep events
+    pub fn new(mut cpu: CPU, link: RunnerLink) -> Runner {
+        cpu.set_audio_sink(Box::new(ChannelSink::new(link.events.clone())));
+        Runner {
+            cpu,
+            rom: Vec::new(),
+            link,
+            paused: false,
+            stopped: false,
+            rewind: Rewind::default(),
+            rewinding: false,
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn rewind(&self) -> &Rewind {
+        &self.rewind
+    }
+
+    /// set_rewind replaces the rewind history, e.g. to change its budget
+    pub fn set_rewind(&mut self, rewind: Rewind) {
+        self.rewind = rewind;
+    }
+
+    /// load_rom resets the CPU and loads the program, which Reset will load again
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), Chip8Error> {
+        self.cpu.reset();
+        self.rewind.clear();
+        self.stopped = false;
+        self.rom = rom;
+        self.cpu.load_program(self.rom.clone())
+    }
+
+    /// spawn runs the runner on a new thread
+    pub fn spawn(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+
+    /// run paces frames at 60 Hz until the frontend quits or goes away
+    pub fn run(&mut self) {
+        let mut limiter = FrameLimiter::new(TIMER_HZ);
+        loop {
+            // a paused or stopped CPU sleeps until the frontend tells it what to do
+            if (self.paused || self.stopped) && !self.rewinding {
+                let command = match self.link.commands.recv() {
+                    Ok(command) => command,
+                    Err(_) => return,
+                };
+                if !self.handle(command) {
+                    return;
+                }
+                continue;
+            }
+            if !self.tick() {
+                return;
+            }
+            limiter.wait();
+        }
+    }
+
+    /// tick applies the pending commands and runs a frame unless paused, or steps back a
+    /// frame while rewinding, without pacing. It returns false once the frontend quit or went
+    /// away.
+    pub fn tick(&mut self) -> bool {
+        if !self.poll_commands() {
+            return false;
+        }
+        if self.rewinding {
+            return self.rewind_frame();
+        }
+        self.paused || self.run_frame()
+    }
+
+    /// poll_commands applies the commands already sent without waiting for more, false when
+    /// the frontend quit or went away
+    pub fn poll_commands(&mut self) -> bool {
+        loop {
+            match self.link.commands.try_recv() {
+                Ok(command) => {
+                    if !self.handle(command) {
+                        return false;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    // apply a command, false on Quit or when the frontend is gone
+    fn handle(&mut self, command: ControlCommand) -> bool {
+        match command {
+            ControlCommand::KeyDown(key) => self.cpu.key_down(key),
+            ControlCommand::KeyUp(key) => self.cpu.key_up(key),
+            ControlCommand::Pause => self.paused = true,
+            ControlCommand::Resume => self.paused = false,
+            ControlCommand::Step => return self.run_frame(),
+            ControlCommand::Reset => {
+                let rom = std::mem::take(&mut self.rom);
+                return self.load_and_report(rom);
+            }
+            ControlCommand::LoadRom(rom) => return self.load_and_report(rom),
+            ControlCommand::SaveState(slot) => {
+                let data = self.cpu.save_state();
+                return self.link.events.send(FrontendEvent::StateSaved { slot, data }).is_ok();
+            }
+            ControlCommand::LoadState(data) => {
+                if let Err(err) = self.cpu.load_state(&data) {
+                    return self.link.events.send(FrontendEvent::StateRejected(err)).is_ok();
+                }
+                self.rewind.clear();
+                self.stopped = self.cpu.is_halted();
+                self.link.publish(&mut self.cpu);
+            }
+            ControlCommand::Rewind(on) => self.rewinding = on,
+            ControlCommand::Quit => return false,
+        }
+        true
+    }
+
+    fn load_and_report(&mut self, rom: Vec<u8>) -> bool {
+        let result = self.load_rom(rom);
+        self.link.publish(&mut self.cpu);
+        if let Err(err) = result {
+            self.stopped = true;
+            return self.link.events.send(FrontendEvent::Halted(Some(err))).is_ok();
+        }
+        true
+    }
+
+    /// run_frame emulates one frame without pacing and publishes the screen if it changed, it
+    /// returns false when the frontend is gone. Headless runs call it directly.
+    pub fn run_frame(&mut self) -> bool {
+        if self.stopped {
+            return true;
+        }
+        let result = self.cpu.run_frame();
+        if self.rewind.due() {
+            self.rewind.push(self.cpu.save_state());
+        }
+        if self.cpu.display().dirty_rows() != 0 {
+            self.link.publish(&mut self.cpu);
+        }
+        match result {
+            Err(err) => {
+                self.stopped = true;
+                self.link.events.send(FrontendEvent::Halted(Some(err))).is_ok()
+            }
+            Ok(()) if self.cpu.is_halted() => {
+                self.stopped = true;
+                self.link.events.send(FrontendEvent::Halted(None)).is_ok()
+            }
+            Ok(()) => true,
+        }
+    }
+
+    // go back to the previous recorded state, the oldest one stays on screen. A state that
+    // does not load drops the whole history, false when the frontend is gone.
+    fn rewind_frame(&mut self) -> bool {
+        let Some(state) = self.rewind.step_back() else {
+            return true;
+        };
+        if let Err(err) = self.cpu.load_state(state) {
+            self.rewind.clear();
+            self.rewinding = false;
+            return self.link.events.send(FrontendEvent::StateRejected(err)).is_ok();
+        }
+        self.stopped = self.cpu.is_halted();
+        self.link.publish(&mut self.cpu);
+        true
+    }
+}
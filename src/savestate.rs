@@ -0,0 +1,352 @@
+// the save state file format, all numbers little-endian:
+//
+//   "C8ST"  magic
+//   u16     format version, VERSION
+//   u64     FNV-1a hash of the ROM the state belongs to
+//   ...     the Snapshot fields in the order of `encode`
+//
+// Any change to the layout bumps VERSION. Only states of the current version are read, older
+// and newer ones are rejected with StateError::UnsupportedVersion.
+
+use crate::audio::AudioPattern;
+use crate::chip8::Snapshot;
+use crate::display::{Display, PLANES, SCREEN_HEIGHT};
+use crate::error::StateError;
+use crate::keypad::Keypad;
+use crate::platform::Platform;
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::rng::Rng;
+use crate::timer::Timers;
+
+const MAGIC: &[u8; 4] = b"C8ST";
+/// the format version written by encode
+pub const VERSION: u16 = 1;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// rom_hash identifies a ROM in the header of its save states
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(FNV_OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// encode serializes a snapshot of the machine running the ROM with hash `rom_hash`
+pub fn encode(snapshot: &Snapshot, rom_hash: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(snapshot.memory.len() + 2048);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&rom_hash.to_le_bytes());
+
+    out.push(match snapshot.platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2,
+    });
+    let quirks = &snapshot.quirks;
+    out.push(quirks.shift_uses_vy as u8);
+    out.push(match quirks.index_increment {
+        IndexIncrement::None => 0,
+        IndexIncrement::X => 1,
+        IndexIncrement::XPlusOne => 2,
+    });
+    out.push(quirks.jump_uses_vx as u8);
+    out.push(quirks.vf_reset as u8);
+    out.push(quirks.clip_sprites as u8);
+    out.push(quirks.display_wait as u8);
+
+    out.extend_from_slice(&snapshot.V);
+    out.extend_from_slice(&snapshot.I.to_le_bytes());
+    out.extend_from_slice(&snapshot.PC.to_le_bytes());
+    out.push(snapshot.SP);
+    out.push(snapshot.timers.delay);
+    out.push(snapshot.timers.sound);
+    for address in snapshot.stack {
+        out.extend_from_slice(&address.to_le_bytes());
+    }
+    out.extend_from_slice(&snapshot.flags);
+    out.push(snapshot.planes);
+    out.extend_from_slice(&snapshot.audio_pattern.buffer);
+    out.push(snapshot.audio_pattern.pitch);
+    out.push(snapshot.halted as u8);
+    out.push(snapshot.waiting_for_key as u8);
+    for bits in snapshot.keypad.to_raw() {
+        out.extend_from_slice(&bits.to_le_bytes());
+    }
+    out.extend_from_slice(&snapshot.rng.state().to_le_bytes());
+    out.extend_from_slice(&snapshot.frame.to_le_bytes());
+
+    out.push(snapshot.display.is_hires() as u8);
+    for plane in 0..PLANES {
+        for y in 0..SCREEN_HEIGHT {
+            out.extend_from_slice(&snapshot.display.row(plane, y).to_le_bytes());
+        }
+    }
+
+    out.extend_from_slice(&(snapshot.memory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&snapshot.memory);
+    out
+}
+
+/// decode parses a save state into the hash of its ROM and the snapshot
+pub fn decode(data: &[u8]) -> Result<(u64, Snapshot), StateError> {
+    let mut input = Input { data };
+    if input.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::NotAState);
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    let rom_hash = input.u64()?;
+
+    let platform = match input.u8()? {
+        0 => Platform::Chip8,
+        1 => Platform::SuperChip,
+        2 => Platform::XoChip,
+        _ => return Err(StateError::Invalid("platform")),
+    };
+    let quirks = Quirks {
+        shift_uses_vy: input.bool()?,
+        index_increment: match input.u8()? {
+            0 => IndexIncrement::None,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::XPlusOne,
+            _ => return Err(StateError::Invalid("index increment quirk")),
+        },
+        jump_uses_vx: input.bool()?,
+        vf_reset: input.bool()?,
+        clip_sprites: input.bool()?,
+        display_wait: input.bool()?,
+    };
+
+    let mut registers = [0; 16];
+    registers.copy_from_slice(input.bytes(16)?);
+    let index = input.u16()?;
+    let pc = input.u16()?;
+    // on CHIP-8 and SCHIP the address space is smaller than what a u16 can hold
+    if index as usize >= platform.memory_size() {
+        return Err(StateError::Invalid("index register"));
+    }
+    if pc as usize >= platform.memory_size() {
+        return Err(StateError::Invalid("program counter"));
+    }
+    let sp = input.u8()?;
+    if sp > 0x10 {
+        return Err(StateError::Invalid("stack pointer"));
+    }
+    let timers = Timers {
+        delay: input.u8()?,
+        sound: input.u8()?,
+    };
+    let mut stack = [0; 16];
+    for address in stack.iter_mut() {
+        *address = input.u16()?;
+        if *address as usize >= platform.memory_size() {
+            return Err(StateError::Invalid("return address"));
+        }
+    }
+    let mut flags = [0; 16];
+    flags.copy_from_slice(input.bytes(16)?);
+    let planes = input.u8()?;
+    if planes > 0b11 {
+        return Err(StateError::Invalid("planes"));
+    }
+    let mut buffer = [0; 16];
+    buffer.copy_from_slice(input.bytes(16)?);
+    let audio_pattern = AudioPattern { buffer, pitch: input.u8()? };
+    let halted = input.bool()?;
+    let waiting_for_key = input.bool()?;
+    let keypad = Keypad::from_raw([input.u16()?, input.u16()?, input.u16()?]);
+    let rng = Rng::new(input.u64()?);
+    let frame = input.u64()?;
+
+    let mut display = Display::new();
+    display.set_hires(input.bool()?);
+    for plane in 0..PLANES {
+        for y in 0..SCREEN_HEIGHT {
+            let mut bytes = [0; 16];
+            bytes.copy_from_slice(input.bytes(16)?);
+            display.set_row(plane, y, u128::from_le_bytes(bytes));
+        }
+    }
+
+    let size = input.u32()? as usize;
+    if size != platform.memory_size() {
+        return Err(StateError::Invalid("memory size"));
+    }
+    let memory = input.bytes(size)?.to_vec();
+    if !input.data.is_empty() {
+        return Err(StateError::Invalid("length"));
+    }
+
+    let snapshot = Snapshot {
+        V: registers,
+        I: index,
+        PC: pc,
+        SP: sp,
+        timers,
+        stack,
+        memory,
+        display,
+        planes,
+        audio_pattern,
+        flags,
+        halted,
+        platform,
+        keypad,
+        waiting_for_key,
+        quirks,
+        rng,
+        frame,
+    };
+    Ok((rom_hash, snapshot))
+}
+
+// a cursor over the state being decoded
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < count {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CPU;
+
+    // offsets into the header and the registers, see encode
+    const VERSION_AT: usize = 4;
+    const PC_AT: usize = 39;
+    const SP_AT: usize = 41;
+    const STACK_AT: usize = 44;
+    const PLANES_AT: usize = 92;
+
+    // 200: call 206, 202: v1 := random, 204: jump 204, 206: v0 += 1, 208: sprite, 20A: return
+    const PROGRAM: [u8; 12] = [0x22, 0x06, 0xC1, 0xFF, 0x12, 0x04, 0x70, 0x01, 0xD0, 0x05, 0x00, 0xEE];
+
+    fn state(platform: Platform) -> (CPU, Vec<u8>) {
+        let mut cpu = CPU::new(platform.quirks());
+        cpu.set_platform(platform);
+        cpu.set_seed(7);
+        cpu.load_program(PROGRAM.to_vec()).unwrap();
+        cpu.run_frame().unwrap();
+        let state = cpu.save_state();
+        (cpu, state)
+    }
+
+    fn invalid(state: &[u8]) -> StateError {
+        decode(state).err().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        for platform in [Platform::Chip8, Platform::SuperChip, Platform::XoChip] {
+            let (cpu, state) = state(platform);
+            let (hash, snapshot) = decode(&state).unwrap();
+            assert_eq!(hash, rom_hash(&PROGRAM));
+            assert_eq!(encode(&snapshot, hash), state);
+            assert_eq!((snapshot.PC, snapshot.V, snapshot.platform), (cpu.pc(), cpu.snapshot().V, platform));
+            assert_eq!(snapshot.display.fingerprint(), cpu.display().fingerprint());
+            assert_eq!(snapshot.memory.len(), platform.memory_size());
+
+            // the machine continues exactly as it would have
+            let mut original = cpu;
+            let mut restored = CPU::new(Quirks::default());
+            restored.load_program(PROGRAM.to_vec()).unwrap();
+            restored.load_state(&state).unwrap();
+            original.run_frame().unwrap();
+            restored.run_frame().unwrap();
+            assert_eq!(restored.save_state(), original.save_state());
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        let (_, state) = state(Platform::Chip8);
+        assert_eq!(invalid(b"C8"), StateError::NotAState);
+        let mut other = state.clone();
+        other[0] = b'X';
+        assert_eq!(invalid(&other), StateError::NotAState);
+        let mut newer = state.clone();
+        newer[VERSION_AT..VERSION_AT + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(invalid(&newer), StateError::UnsupportedVersion(VERSION + 1));
+    }
+
+    #[test]
+    fn truncated() {
+        let (_, state) = state(Platform::Chip8);
+        for len in [6, PC_AT, STACK_AT + 3, state.len() - 1] {
+            assert_eq!(invalid(&state[..len]), StateError::Truncated, "{} bytes", len);
+        }
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(invalid(&longer), StateError::Invalid("length"));
+    }
+
+    #[test]
+    fn rom_mismatch() {
+        let (_, state) = state(Platform::Chip8);
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_program(vec![0x12, 0x00]).unwrap();
+        let before = cpu.save_state();
+        assert_eq!(
+            cpu.load_state(&state),
+            Err(StateError::RomMismatch { expected: rom_hash(&[0x12, 0x00]), found: rom_hash(&PROGRAM) })
+        );
+        assert_eq!(cpu.save_state(), before);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let (_, state) = state(Platform::Chip8);
+        let patched = |at: usize, bytes: &[u8]| {
+            let mut state = state.clone();
+            state[at..at + bytes.len()].copy_from_slice(bytes);
+            state
+        };
+        assert_eq!(invalid(&patched(SP_AT, &[0x11])), StateError::Invalid("stack pointer"));
+        assert_eq!(invalid(&patched(PC_AT, &0x1000u16.to_le_bytes())), StateError::Invalid("program counter"));
+        assert_eq!(invalid(&patched(PC_AT - 2, &0x1000u16.to_le_bytes())), StateError::Invalid("index register"));
+        assert_eq!(invalid(&patched(STACK_AT + 30, &0x1000u16.to_le_bytes())), StateError::Invalid("return address"));
+        assert_eq!(invalid(&patched(PLANES_AT, &[4])), StateError::Invalid("planes"));
+        // the top of the 4K is fine
+        assert!(decode(&patched(PC_AT, &0x0FFEu16.to_le_bytes())).is_ok());
+    }
+}
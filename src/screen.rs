@@ -0,0 +1,11 @@
+use crate::display::Display;
+
+/// ScreenTrait is implemented by the frontends that show what the CPU draws. `render` runs
+/// the frontend, consuming the events sent by a `Runner` until it stops.
+pub trait ScreenTrait {
+    fn clear(&mut self);
+    fn draw_screen(&mut self, display: &Display);
+    fn draw_point(&mut self, x: i32, y: i32);
+    fn draw(&mut self, text : &str);
+    fn render(&mut self);
+}
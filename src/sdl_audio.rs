@@ -0,0 +1,180 @@
+extern crate sdl2;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::Sdl;
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::audio::{AudioPattern, AudioSink};
+
+const SAMPLE_RATE: i32 = 44100;
+// fade in and out over this many seconds so starting and stopping the tone doesn't click
+const RAMP_SECONDS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneSettings {
+    pub waveform: Waveform,
+    pub frequency: f32, //  Hz
+    pub volume: f32,    //  0.0..=1.0
+    pub muted: bool,
+}
+
+impl Default for ToneSettings {
+    fn default() -> ToneSettings {
+        ToneSettings {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+// runs on the SDL audio thread
+struct Beeper {
+    on: Arc<AtomicBool>,
+    settings: Arc<Mutex<ToneSettings>>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+    sample_rate: f32,
+    phase: f32,     //  0.0..1.0
+    position: f32,  //  bit position inside the XO-CHIP pattern
+    amplitude: f32, //  current amplitude, ramps towards the target
+}
+
+impl AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let settings = *self.settings.lock().unwrap();
+        let pattern = *self.pattern.lock().unwrap();
+        let target = if self.on.load(Ordering::Relaxed) && !settings.muted {
+            settings.volume.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let ramp_step = 1.0 / (RAMP_SECONDS * self.sample_rate);
+        let phase_step = settings.frequency / self.sample_rate;
+
+        for sample in out.iter_mut() {
+            if self.amplitude < target {
+                self.amplitude = (self.amplitude + ramp_step).min(target);
+            } else if self.amplitude > target {
+                self.amplitude = (self.amplitude - ramp_step).max(target);
+            }
+
+            // an XO-CHIP program that loaded a pattern replaces the plain tone
+            let wave = match (pattern, settings.waveform) {
+                (Some(pattern), _) => if pattern.bit(self.position as usize) { 1.0 } else { -1.0 },
+                (None, Waveform::Square) => if self.phase < 0.5 { 1.0 } else { -1.0 },
+                (None, Waveform::Sine) => (self.phase * TAU).sin(),
+            };
+            *sample = wave * self.amplitude;
+
+            self.phase = (self.phase + phase_step).fract();
+            if let Some(pattern) = pattern {
+                self.position = (self.position + pattern.rate() / self.sample_rate) % 128.0;
+            }
+        }
+    }
+}
+
+/// SdlAudio owns the SDL playback device. It has to stay on the thread that created it,
+/// the CPU talks to it through the `Send`able sink returned by `sink`.
+pub struct SdlAudio {
+    on: Arc<AtomicBool>,
+    settings: Arc<Mutex<ToneSettings>>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+    _device: AudioDevice<Beeper>,
+}
+
+/// SdlAudioSink switches the tone of an `SdlAudio` device on and off.
+pub struct SdlAudioSink {
+    on: Arc<AtomicBool>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+}
+
+impl AudioSink for SdlAudioSink {
+    fn set_tone(&mut self, on: bool) {
+        self.on.store(on, Ordering::Relaxed);
+    }
+
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        *self.pattern.lock().unwrap() = pattern;
+    }
+}
+
+impl SdlAudio {
+    pub fn new(sdl_context: &Sdl, settings: ToneSettings) -> Result<SdlAudio, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: None,
+        };
+
+        let on = Arc::new(AtomicBool::new(false));
+        let settings = Arc::new(Mutex::new(settings));
+        let pattern = Arc::new(Mutex::new(None));
+        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| Beeper {
+            on: on.clone(),
+            settings: settings.clone(),
+            pattern: pattern.clone(),
+            sample_rate: spec.freq as f32,
+            phase: 0.0,
+            position: 0.0,
+            amplitude: 0.0,
+        })?;
+        // the device plays silence while the tone is off, so it can run all the time
+        device.resume();
+
+        Ok(SdlAudio {
+            on,
+            settings,
+            pattern,
+            _device: device,
+        })
+    }
+
+    pub fn sink(&self) -> SdlAudioSink {
+        SdlAudioSink {
+            on: self.on.clone(),
+            pattern: self.pattern.clone(),
+        }
+    }
+
+    /// set_tone switches the tone on and off, like the sink does from another thread
+    pub fn set_tone(&self, on: bool) {
+        self.on.store(on, Ordering::Relaxed);
+    }
+
+    /// set_pattern replaces the tone with an XO-CHIP audio pattern, None brings the tone back
+    pub fn set_pattern(&self, pattern: Option<AudioPattern>) {
+        *self.pattern.lock().unwrap() = pattern;
+    }
+
+    pub fn settings(&self) -> ToneSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    pub fn set_waveform(&self, waveform: Waveform) {
+        self.settings.lock().unwrap().waveform = waveform;
+    }
+
+    pub fn set_frequency(&self, frequency: f32) {
+        self.settings.lock().unwrap().frequency = frequency;
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.settings.lock().unwrap().volume = volume;
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.settings.lock().unwrap().muted = muted;
+    }
+}
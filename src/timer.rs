@@ -0,0 +1,62 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the delay and sound timers count down at this rate, it is also the emulated frame rate
+pub const TIMER_HZ: u32 = 60;
+/// how many instructions run per frame unless set otherwise, i.e. 600 instructions per second
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+/// Timers holds the delay and sound timers. They are decremented once per emulated frame,
+/// so their rate only depends on how many frames were run and never on the wall clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers::default()
+    }
+
+    /// tick counts both timers down by one, stopping at zero
+    pub fn tick(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+
+    /// the buzzer sounds while the sound timer is non-zero
+    pub fn sound_active(&self) -> bool {
+        self.sound > 0
+    }
+}
+
+/// FrameLimiter paces a loop to a fixed rate against the wall clock. Only the interactive
+/// frontends use it; headless runs call `CPU::run_frame` as fast as they like.
+pub struct FrameLimiter {
+    frame: Duration,
+    deadline: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(rate: u32) -> FrameLimiter {
+        let frame = Duration::from_secs(1) / rate;
+        FrameLimiter {
+            frame,
+            deadline: Instant::now() + frame,
+        }
+    }
+
+    /// wait sleeps until the end of the current frame
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if now < self.deadline {
+            thread::sleep(self.deadline - now);
+            self.deadline += self.frame;
+        } else {
+            // we fell behind (e.g. the process was suspended), start over instead of
+            // running a burst of frames to catch up
+            self.deadline = now + self.frame;
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+// a triple buffer hands the latest value from a producer to a consumer without either of them
+// waiting for the other: the writer fills its back buffer and swaps it with the middle one,
+// the reader swaps its front buffer with the middle one when a new value was published.
+// Values the reader didn't get to in time are overwritten, so nothing queues up.
+
+struct Middle<T> {
+    value: T,
+    fresh: bool,    //  published and not read yet
+}
+
+/// Writer is the producing end of a triple buffer.
+pub struct Writer<T> {
+    back: T,
+    middle: Arc<Mutex<Middle<T>>>,
+}
+
+/// Reader is the consuming end of a triple buffer.
+pub struct Reader<T> {
+    front: T,
+    middle: Arc<Mutex<Middle<T>>>,
+}
+
+/// triple_buffer creates a connected writer and reader, all three buffers start as `initial`
+pub fn triple_buffer<T: Clone>(initial: T) -> (Writer<T>, Reader<T>) {
+    let middle = Arc::new(Mutex::new(Middle {
+        value: initial.clone(),
+        fresh: false,
+    }));
+    let writer = Writer {
+        back: initial.clone(),
+        middle: middle.clone(),
+    };
+    let reader = Reader {
+        front: initial,
+        middle,
+    };
+    (writer, reader)
+}
+
+impl<T> Writer<T> {
+    /// the buffer to fill before calling publish, it holds an old value
+    pub fn back_mut(&mut self) -> &mut T {
+        &mut self.back
+    }
+
+    /// publish makes the back buffer the latest value, replacing an unread one
+    pub fn publish(&mut self) {
+        let mut middle = self.middle.lock().unwrap();
+        mem::swap(&mut self.back, &mut middle.value);
+        middle.fresh = true;
+    }
+}
+
+impl<T> Reader<T> {
+    /// update takes the latest published value if there is a new one and tells whether
+    /// there was
+    pub fn update(&mut self) -> bool {
+        let mut middle = self.middle.lock().unwrap();
+        if !middle.fresh {
+            return false;
+        }
+        mem::swap(&mut self.front, &mut middle.value);
+        middle.fresh = false;
+        true
+    }
+
+    /// the value taken by the last update
+    pub fn front(&self) -> &T {
+        &self.front
+    }
+}
@@ -0,0 +1,31 @@
+use chip8emu_rs::{protocol, HeadlessScreen, Quirks, Runner, CPU};
+
+const ROM: &[u8] = include_bytes!("danm8ku.ch8");
+
+// run the ROM without a window for `frames` frames, with Cxkk seeded so the run repeats
+fn run(frames: u32) -> HeadlessScreen {
+    let (runner_link, frontend_link) = protocol::link();
+    let mut cpu = CPU::new(Quirks::default());
+    cpu.set_seed(0x5EED);
+    let mut runner = Runner::new(cpu, runner_link);
+    runner.load_rom(ROM.to_vec()).unwrap();
+    let mut screen = HeadlessScreen::new(frontend_link);
+    for _ in 0..frames {
+        assert!(runner.run_frame());
+        screen.update();
+    }
+    screen
+}
+
+#[test]
+fn danm8ku_fingerprint() {
+    let screen = run(300);
+    assert!(screen.halted().is_none());
+    assert!(screen.display().lit_pixels() > 0);
+    assert_eq!(screen.display().fingerprint(), 0x4C7A_DECD_E771_729A);
+}
+
+#[test]
+fn runs_repeat() {
+    assert_eq!(run(120).display().fingerprint(), run(120).display().fingerprint());
+}